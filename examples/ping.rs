@@ -22,12 +22,13 @@ fn main() {
     loop {
         match results.recv() {
             Ok(result) => match result {
-                Idle { addr } => {
+                Idle { addr, .. } => {
                     error!("Idle Address {}.", addr);
                 }
-                Receive { addr, rtt } => {
+                Receive { addr, rtt, .. } => {
                     info!("Receive from Address {} in {:?}.", addr, rtt);
                 }
+                _ => {}
             },
             Err(_) => panic!("Worker threads disconnected before the solution was found!"),
         }
@@ -0,0 +1,438 @@
+// fastping: ping many hosts at once with the fastping-rs engine, taking the core
+// fping options.  Exits 0 when every target answered, 1 when some didn't, 2 when
+// some names couldn't be resolved, 3 on bad arguments and 4 when the pinger
+// couldn't be set up
+extern crate fastping_rs;
+extern crate getopts;
+
+use fastping_rs::{hosts, PingResult, PingerBuilder};
+use getopts::{Matches, Options};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: fastping [options] [targets...]";
+// -b counts the data after the icmp header, up to what fits in an ipv4 datagram
+const ICMP_HEADER_SIZE: u64 = 8;
+const MAX_DATA_SIZE: u64 = 65507;
+
+// a target as given on the command line and the address it resolved to
+struct Target {
+    name: String,
+    addr: IpAddr,
+}
+
+// replies and round trip times seen for a target
+#[derive(Default)]
+struct Stats {
+    sent: u32,
+    received: u32,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    total: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, rtt: Duration) {
+        self.received += 1;
+        self.total += rtt;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+    }
+
+    fn avg(&self) -> Option<Duration> {
+        if self.received == 0 {
+            None
+        } else {
+            Some(self.total / self.received)
+        }
+    }
+
+    fn loss(&self) -> u32 {
+        ((self.sent - self.received) * 100)
+            .checked_div(self.sent)
+            .unwrap_or(0)
+    }
+}
+
+fn main() {
+    process::exit(run(env::args().skip(1).collect()));
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optflag("a", "alive", "show targets that are alive");
+    opts.optflag("u", "unreach", "show targets that are unreachable");
+    opts.optopt("c", "count", "number of pings to send to each target", "N");
+    opts.optopt(
+        "p",
+        "period",
+        "milliseconds between pings to a target (1000)",
+        "MSEC",
+    );
+    opts.optopt(
+        "t",
+        "timeout",
+        "milliseconds to wait for a reply (500)",
+        "MSEC",
+    );
+    opts.optopt("r", "retry", "retries when not counting (3)", "N");
+    opts.optopt("b", "size", "ping data size in bytes (56)", "BYTES");
+    opts.optopt("f", "file", "read targets from a file", "FILE");
+    opts.optflag(
+        "g",
+        "generate",
+        "generate targets from a network in cidr notation",
+    );
+    opts.optflag("s", "stats", "print final stats");
+    opts.optflag("q", "quiet", "don't show per-ping results");
+    opts.optflag("h", "help", "show this help");
+    opts
+}
+
+fn run(args: Vec<String>) -> i32 {
+    let opts = options();
+    let matches = match opts.parse(&args) {
+        Ok(matches) => matches,
+        Err(e) => {
+            eprintln!("fastping: {}\n{}", e, opts.usage(USAGE));
+            return 3;
+        }
+    };
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(USAGE));
+        return 0;
+    }
+    let number = |name: &str, default: u64| -> Result<u64, String> {
+        match matches.opt_str(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("invalid -{} value {}", name, value)),
+            None => Ok(default),
+        }
+    };
+    let (count, period, timeout, retries, size) = match (
+        number("c", 0),
+        number("p", 1000),
+        number("t", 500),
+        number("r", 3),
+        number("b", 56),
+    ) {
+        (Ok(c), Ok(p), Ok(t), Ok(r), Ok(b)) => (c, p, t, r, b),
+        (c, p, t, r, b) => {
+            for e in [c, p, t, r, b].iter().filter_map(|n| n.clone().err()) {
+                eprintln!("fastping: {}", e);
+            }
+            return 3;
+        }
+    };
+    if size > MAX_DATA_SIZE {
+        eprintln!(
+            "fastping: invalid -b value {}, at most {}",
+            size, MAX_DATA_SIZE
+        );
+        return 3;
+    }
+
+    let names = match target_names(&matches) {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!("fastping: {}", e);
+            return 3;
+        }
+    };
+    let mut targets = Vec::new();
+    let mut unknown = 0;
+    for name in names {
+        match resolve(&name) {
+            Some(addr) => targets.push(Target { name, addr }),
+            None => {
+                eprintln!("{}: Name or service not known", name);
+                unknown += 1;
+            }
+        }
+    }
+
+    // fping only retries when it isn't counting
+    let mut builder = PingerBuilder::new()
+        .max_rtt(timeout)
+        .size((ICMP_HEADER_SIZE + size) as usize);
+    if count == 0 {
+        builder = builder.retries(retries as u32);
+    }
+    let (pinger, results) = match builder.build() {
+        Ok(built) => built,
+        Err(e) => {
+            eprintln!("fastping: {}", e);
+            return 4;
+        }
+    };
+    for target in &targets {
+        pinger.add_ipaddr(&target.addr.to_string());
+    }
+
+    let quiet = matches.opt_present("q");
+    let listing = matches.opt_present("a") || matches.opt_present("u");
+    let mut stats: BTreeMap<IpAddr, Stats> = BTreeMap::new();
+    for seq in 0..count.max(1) {
+        let started = Instant::now();
+        pinger.ping_once();
+        for result in results.try_iter() {
+            tally(&mut stats, &result);
+            if let PingResult::Receive {
+                addr, rtt, size, ..
+            } = result
+            {
+                if count > 0 && !quiet {
+                    let target_stats = &stats[&addr];
+                    println!(
+                        "{} : [{}], {} bytes, {} ms ({} avg, {}% loss)",
+                        name(&targets, addr),
+                        seq,
+                        size,
+                        millis(rtt),
+                        millis(target_stats.avg().unwrap_or_default()),
+                        target_stats.loss()
+                    );
+                }
+            }
+        }
+        if seq + 1 < count {
+            let period = Duration::from_millis(period);
+            if let Some(wait) = period.checked_sub(started.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    }
+
+    let alive = |target: &Target| stats.get(&target.addr).map_or(0, |s| s.received) > 0;
+    for target in &targets {
+        if listing {
+            if (matches.opt_present("a") && alive(target))
+                || (matches.opt_present("u") && !alive(target))
+            {
+                println!("{}", target.name);
+            }
+        } else if count == 0 && !quiet {
+            let state = if alive(target) {
+                "alive"
+            } else {
+                "unreachable"
+            };
+            println!("{} is {}", target.name, state);
+        }
+    }
+    if count > 0 {
+        for target in &targets {
+            if let Some(target_stats) = stats.get(&target.addr) {
+                eprintln!("{}", summary(&target.name, target_stats));
+            }
+        }
+    }
+    if matches.opt_present("s") {
+        let reachable = targets.iter().filter(|t| alive(t)).count();
+        let mut all = Stats::default();
+        for target_stats in stats.values() {
+            all.sent += target_stats.sent;
+            all.received += target_stats.received;
+            all.total += target_stats.total;
+            all.min = match (all.min, target_stats.min) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            all.max = all.max.max(target_stats.max);
+        }
+        eprintln!();
+        eprintln!(" {:>7} targets", targets.len() + unknown);
+        eprintln!(" {:>7} alive", reachable);
+        eprintln!(" {:>7} unreachable", targets.len() - reachable);
+        eprintln!(" {:>7} unknown addresses", unknown);
+        eprintln!();
+        eprintln!(" {:>7} ICMP Echos sent", all.sent);
+        eprintln!(" {:>7} ICMP Echo Replies received", all.received);
+        eprintln!();
+        eprintln!(
+            " {:>7} ms (min round trip time)",
+            millis(all.min.unwrap_or_default())
+        );
+        eprintln!(
+            " {:>7} ms (avg round trip time)",
+            millis(all.avg().unwrap_or_default())
+        );
+        eprintln!(
+            " {:>7} ms (max round trip time)",
+            millis(all.max.unwrap_or_default())
+        );
+    }
+
+    if unknown > 0 {
+        2
+    } else if targets.iter().all(alive) {
+        0
+    } else {
+        1
+    }
+}
+
+// the targets named by the arguments, a file or stdin, expanding networks with -g
+fn target_names(matches: &Matches) -> Result<Vec<String>, String> {
+    if matches.opt_present("g") {
+        let mut names = Vec::new();
+        for network in &matches.free {
+            names.extend(hosts(network)?.iter().map(|addr| addr.to_string()));
+        }
+        return Ok(names);
+    }
+    if let Some(path) = matches.opt_str("f") {
+        let file = File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+        return read_names(BufReader::new(file)).map_err(|e| e.to_string());
+    }
+    if !matches.free.is_empty() {
+        return Ok(matches.free.clone());
+    }
+    let stdin = io::stdin();
+    let names = read_names(stdin.lock()).map_err(|e| e.to_string())?;
+    Ok(names)
+}
+
+// one target per line, skipping blank lines and # comments
+fn read_names<R: BufRead>(reader: R) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let name = line.split('#').next().unwrap_or("").trim();
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    Ok(names)
+}
+
+fn resolve(name: &str) -> Option<IpAddr> {
+    if let Ok(addr) = name.parse::<IpAddr>() {
+        return Some(addr);
+    }
+    (name, 0)
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .map(|addr| addr.ip())
+}
+
+// add a reply or timeout to its target's stats, every attempt (retries included)
+// counting as sent
+fn tally(stats: &mut BTreeMap<IpAddr, Stats>, result: &PingResult) {
+    match *result {
+        PingResult::Receive {
+            addr,
+            rtt,
+            attempts,
+            ..
+        } => {
+            let target_stats = stats.entry(addr).or_default();
+            target_stats.sent += attempts;
+            target_stats.add(rtt);
+        }
+        PingResult::Idle { addr, attempts, .. } => stats.entry(addr).or_default().sent += attempts,
+        _ => {}
+    }
+}
+
+fn name(targets: &[Target], addr: IpAddr) -> String {
+    targets
+        .iter()
+        .find(|target| target.addr == addr)
+        .map_or_else(|| addr.to_string(), |target| target.name.clone())
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}", duration.as_secs_f64() * 1000.0)
+}
+
+// fping's per-target summary line in count mode
+fn summary(name: &str, stats: &Stats) -> String {
+    let mut line = format!(
+        "{} : xmt/rcv/%loss = {}/{}/{}%",
+        name,
+        stats.sent,
+        stats.received,
+        stats.loss()
+    );
+    if let (Some(min), Some(avg), Some(max)) = (stats.min, stats.avg(), stats.max) {
+        line.push_str(&format!(
+            ", min/avg/max = {}/{}/{}",
+            millis(min),
+            millis(avg),
+            millis(max)
+        ));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fastping_rs::{Binding, Probe};
+
+    #[test]
+    fn test_read_names() {
+        let input = "8.8.8.8\n\n# resolvers\n1.1.1.1 # cloudflare\n";
+        assert_eq!(
+            read_names(input.as_bytes()).unwrap(),
+            vec!["8.8.8.8".to_string(), "1.1.1.1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut stats = Stats {
+            sent: 4,
+            ..Stats::default()
+        };
+        stats.add(Duration::from_micros(1500));
+        stats.add(Duration::from_micros(2500));
+        assert_eq!(
+            summary("host", &stats),
+            "host : xmt/rcv/%loss = 4/2/50%, min/avg/max = 1.50/2.00/2.50"
+        );
+        assert_eq!(
+            summary("host", &Stats::default()),
+            "host : xmt/rcv/%loss = 0/0/0%"
+        );
+
+        // a target retried twice in each of two rounds
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut stats = BTreeMap::new();
+        for round_id in 1..3 {
+            let idle = PingResult::Idle {
+                addr,
+                binding: Binding::default(),
+                attempts: 3,
+                round_id,
+                probe: Probe::Echo,
+            };
+            tally(&mut stats, &idle);
+        }
+        assert_eq!(
+            summary("host", &stats[&addr]),
+            "host : xmt/rcv/%loss = 6/0/100%"
+        );
+    }
+
+    #[test]
+    fn test_run() {
+        assert_eq!(run(vec!["-c".to_string(), "x".to_string()]), 3);
+        let args = |args: &str| args.split_whitespace().map(String::from).collect();
+        assert_eq!(run(args("-q -b 65508 127.0.0.1")), 3);
+        assert_eq!(run(args("-q -t 200 -b 0 127.0.0.1")), 0);
+        assert_eq!(run(args("-q -t 200 127.0.0.1")), 0);
+        assert_eq!(run(args("-q -t 100 -r 0 127.0.0.1 7.7.7.7")), 1);
+        assert_eq!(run(args("-q -c 2 -p 100 -t 50 127.0.0.1")), 0);
+        assert_eq!(run(args("-q -t 100 no-such-host.invalid")), 2);
+    }
+}
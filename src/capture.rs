@@ -0,0 +1,311 @@
+// Packet capture for debugging: the echo requests sent and the icmp messages the
+// listeners read are written to a pcap file Wireshark can open.  The kernel writes
+// and strips the ip headers of the regular sockets, so the capture gets headers
+// synthesized from what's known of the packet: the target, the binding's source
+// (unspecified if unbound) or the destination a reply arrived for, the ttl and tos.
+// Received packets are stamped with the time the listener read them.  The ipv4 identification and the
+// icmpv6 checksum of sent probes, which the kernel fills in, are left at 0
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, MutableIpv4Packet};
+use pnet::packet::ipv6::MutableIpv6Packet;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// LINKTYPE_RAW: packets start at the ip header, version 4 or 6
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+const FILE_HEADER_LEN: u64 = 24;
+const RECORD_HEADER_LEN: u64 = 16;
+
+// where PingerBuilder::capture writes packets to and how much of them to keep
+#[derive(Clone, Debug)]
+pub struct CaptureOptions {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_files: usize,
+}
+
+impl CaptureOptions {
+    pub fn new<P: AsRef<Path>>(path: P) -> CaptureOptions {
+        CaptureOptions {
+            path: path.as_ref().to_path_buf(),
+            max_bytes: None,
+            max_files: 1,
+        }
+    }
+
+    // rotate to a new file once the current one would grow past max_bytes.  Unset
+    // lets the file grow without limit
+    pub fn max_bytes(mut self, max_bytes: u64) -> CaptureOptions {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    // rotated files to keep, as path.1 (the newest) to path.N, on top of the one
+    // being written.  Default is 1
+    pub fn max_files(mut self, max_files: usize) -> CaptureOptions {
+        self.max_files = max_files;
+        self
+    }
+}
+
+// a pcap file being written, shared between the pinger's sending thread and its
+// listeners
+pub struct Capture {
+    options: CaptureOptions,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl Capture {
+    // create (truncate) the capture file and write its header
+    pub fn create(options: CaptureOptions) -> io::Result<Capture> {
+        let writer = open(&options.path)?;
+        Ok(Capture {
+            options,
+            writer,
+            written: FILE_HEADER_LEN,
+        })
+    }
+
+    // an icmp message sent to destination from the binding's source
+    pub fn sent(
+        &mut self,
+        source: Option<IpAddr>,
+        destination: IpAddr,
+        ttl: Option<u8>,
+        tos: Option<u8>,
+        icmp: &[u8],
+    ) {
+        let packet = synthesize(
+            source,
+            Some(destination),
+            destination.is_ipv6(),
+            ttl,
+            tos,
+            icmp,
+        );
+        self.write_or_log(&packet, SystemTime::now());
+    }
+
+    // an icmp message a listener read from source at the given time
+    pub fn received(
+        &mut self,
+        source: IpAddr,
+        destination: Option<IpAddr>,
+        ttl: Option<u8>,
+        icmp: &[u8],
+        at: SystemTime,
+    ) {
+        let packet = synthesize(Some(source), destination, source.is_ipv6(), ttl, None, icmp);
+        self.write_or_log(&packet, at);
+    }
+
+    // a whole ip packet, as sent by layer 3 targets
+    #[cfg(feature = "layer3")]
+    pub fn packet(&mut self, packet: &[u8]) {
+        self.write_or_log(packet, SystemTime::now());
+    }
+
+    fn write_or_log(&mut self, packet: &[u8], at: SystemTime) {
+        if let Err(e) = self.write(packet, at) {
+            error!("Error writing packet capture: {}", e);
+        }
+    }
+
+    fn write(&mut self, packet: &[u8], at: SystemTime) -> io::Result<()> {
+        let length = RECORD_HEADER_LEN + packet.len() as u64;
+        if let Some(max_bytes) = self.options.max_bytes {
+            if self.written > FILE_HEADER_LEN && self.written + length > max_bytes {
+                self.rotate()?;
+            }
+        }
+        let since = at.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+        let mut record = Vec::with_capacity(length as usize);
+        record.extend_from_slice(&(since.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&since.subsec_micros().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(packet);
+        self.writer.write_all(&record)?;
+        // flushed per packet so the capture can be read while the pinger runs
+        self.writer.flush()?;
+        self.written += length;
+        Ok(())
+    }
+
+    // shift path.N-1 to path.N, ..., path to path.1 and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let path = &self.options.path;
+        let rotated = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.options.max_files == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.options.max_files).rev() {
+                if rotated(n).exists() {
+                    fs::rename(rotated(n), rotated(n + 1))?;
+                }
+            }
+            fs::rename(path, rotated(1))?;
+        }
+        self.writer = open(path)?;
+        self.written = FILE_HEADER_LEN;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut header = Vec::with_capacity(FILE_HEADER_LEN as usize);
+    header.extend_from_slice(&0xa1b2_c3d4u32.to_le_bytes());
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&4u16.to_le_bytes());
+    // utc, no timestamp accuracy given
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&SNAPLEN.to_le_bytes());
+    header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    writer.write_all(&header)?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+// wrap an icmp message in an ip header, with unspecified addresses for the ones
+// not known or of the wrong family
+fn synthesize(
+    source: Option<IpAddr>,
+    destination: Option<IpAddr>,
+    ipv6: bool,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    icmp: &[u8],
+) -> Vec<u8> {
+    let ttl = ttl.unwrap_or(64);
+    let tos = tos.unwrap_or(0);
+    if !ipv6 {
+        let v4 = |addr: Option<IpAddr>| match addr {
+            Some(IpAddr::V4(addr)) => addr,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        let mut buffer = vec![0; 20 + icmp.len()];
+        {
+            let mut packet = MutableIpv4Packet::new(&mut buffer).unwrap();
+            packet.set_version(4);
+            packet.set_header_length(5);
+            packet.set_dscp(tos >> 2);
+            packet.set_ecn(tos & 3);
+            packet.set_total_length((20 + icmp.len()) as u16);
+            packet.set_ttl(ttl);
+            packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            packet.set_source(v4(source));
+            packet.set_destination(v4(destination));
+            packet.set_payload(icmp);
+            let checksum = ipv4::checksum(&packet.to_immutable());
+            packet.set_checksum(checksum);
+        }
+        buffer
+    } else {
+        let v6 = |addr: Option<IpAddr>| match addr {
+            Some(IpAddr::V6(addr)) => addr,
+            _ => Ipv6Addr::UNSPECIFIED,
+        };
+        let mut buffer = vec![0; 40 + icmp.len()];
+        {
+            let mut packet = MutableIpv6Packet::new(&mut buffer).unwrap();
+            packet.set_version(6);
+            packet.set_traffic_class(tos);
+            packet.set_payload_length(icmp.len() as u16);
+            packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+            packet.set_hop_limit(ttl);
+            packet.set_source(v6(source));
+            packet.set_destination(v6(destination));
+            packet.set_payload(icmp);
+        }
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::ipv4::Ipv4Packet;
+    use std::env;
+    use std::process;
+
+    // the packets in a capture file, after checking its header
+    fn packets(path: &Path) -> Vec<Vec<u8>> {
+        let data = fs::read(path).unwrap();
+        assert_eq!(&data[..4], &0xa1b2_c3d4u32.to_le_bytes());
+        assert_eq!(&data[20..24], &LINKTYPE_RAW.to_le_bytes());
+        let mut packets = Vec::new();
+        let mut i = FILE_HEADER_LEN as usize;
+        while i < data.len() {
+            let length = u32::from_le_bytes([data[i + 8], data[i + 9], data[i + 10], data[i + 11]]);
+            let start = i + RECORD_HEADER_LEN as usize;
+            packets.push(data[start..start + length as usize].to_vec());
+            i = start + length as usize;
+        }
+        packets
+    }
+
+    #[test]
+    fn test_capture() {
+        let path = env::temp_dir().join(format!("fastping-capture-{}.pcap", process::id()));
+        let mut capture = Capture::create(CaptureOptions::new(&path)).unwrap();
+        let source = "192.0.2.100".parse::<IpAddr>().unwrap();
+        let target = "192.0.2.1".parse::<IpAddr>().unwrap();
+        capture.sent(Some(source), target, Some(32), Some(46 << 2), &[8, 0, 0, 0]);
+        let received_at = UNIX_EPOCH + Duration::new(1_000_000, 250_000_000);
+        capture.received(target, Some(source), Some(60), &[0, 0, 0, 0], received_at);
+        capture.sent(None, "2001:db8::1".parse().unwrap(), None, None, &[128, 0]);
+
+        let packets = packets(&path);
+        assert_eq!(packets.len(), 3);
+        let sent = Ipv4Packet::new(&packets[0]).unwrap();
+        assert_eq!(IpAddr::V4(sent.get_source()), source);
+        assert_eq!(IpAddr::V4(sent.get_destination()), target);
+        assert_eq!((sent.get_ttl(), sent.get_dscp()), (32, 46));
+        assert_eq!(sent.get_checksum(), ipv4::checksum(&sent));
+        assert_eq!(sent.get_total_length(), 24);
+        let received = Ipv4Packet::new(&packets[1]).unwrap();
+        assert_eq!(IpAddr::V4(received.get_source()), target);
+        assert_eq!(IpAddr::V4(received.get_destination()), source);
+        // stamped with the time it was read, after the 24 byte first packet
+        let data = fs::read(&path).unwrap();
+        let at = &data[FILE_HEADER_LEN as usize + RECORD_HEADER_LEN as usize + 24..][..8];
+        assert_eq!(at, [64, 66, 15, 0, 144, 208, 3, 0]);
+        assert_eq!(received.get_checksum(), ipv4::checksum(&received));
+        assert_eq!(packets[2][0] >> 4, 6);
+        assert_eq!(packets[2].len(), 42);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = env::temp_dir().join(format!("fastping-rotation-{}.pcap", process::id()));
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", path.display(), n));
+        // room for two 20 byte packets per file
+        let options = CaptureOptions::new(&path).max_bytes(100).max_files(2);
+        let mut capture = Capture::create(options).unwrap();
+        let target = "192.0.2.1".parse::<IpAddr>().unwrap();
+        for _ in 0..7 {
+            capture.received(target, None, None, &[], SystemTime::now());
+        }
+        assert_eq!(packets(&path).len(), 1);
+        assert_eq!(packets(&rotated(1)).len(), 2);
+        assert_eq!(packets(&rotated(2)).len(), 2);
+        assert!(!rotated(3).exists());
+        for file in [path.clone(), rotated(1), rotated(2)].iter() {
+            assert!(fs::metadata(file).unwrap().len() <= 100);
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// What send_pings reports as it goes.  With the tracing feature these are structured
+// events with addr, seq, ident and rtt_us fields inside a span per round, which
+// carries the round field; without it they're the plain log messages
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// guard for the round in progress, the round's span while tracing
+#[cfg(feature = "tracing")]
+pub type Round = ::tracing::span::EnteredSpan;
+#[cfg(not(feature = "tracing"))]
+pub struct Round;
+
+#[cfg(feature = "tracing")]
+pub fn round(round_id: u64) -> Round {
+    ::tracing::debug_span!("round", round = round_id).entered()
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn round(_round_id: u64) -> Round {
+    Round
+}
+
+#[cfg(feature = "tracing")]
+pub fn sent(addr: IpAddr, ident: u16, seq: u16, attempt: u32) {
+    ::tracing::trace!(%addr, ident, seq, attempt, "probe sent");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn sent(addr: IpAddr, ident: u16, seq: u16, attempt: u32) {
+    trace!(
+        "Sent probe {} (identifier {}, attempt {}) to {}",
+        seq,
+        ident,
+        attempt,
+        addr
+    );
+}
+
+#[cfg(feature = "tracing")]
+pub fn send_failed(addr: IpAddr, e: &dyn Display) {
+    ::tracing::error!(%addr, error = %e, "failed to send probe");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn send_failed(addr: IpAddr, e: &dyn Display) {
+    error!("Failed to send ping to {:?}: {}", addr, e);
+}
+
+#[cfg(feature = "tracing")]
+pub fn received(addr: IpAddr, ident: u16, seq: u16, rtt: Duration) {
+    ::tracing::debug!(%addr, ident, seq, rtt_us = rtt.as_micros() as u64, "reply received");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn received(addr: IpAddr, ident: u16, seq: u16, rtt: Duration) {
+    trace!(
+        "Received reply {} (identifier {}) from {} in {:?}",
+        seq,
+        ident,
+        addr,
+        rtt
+    );
+}
+
+// a reply from a target that doesn't answer its latest probe
+#[cfg(feature = "tracing")]
+pub fn mismatch(addr: IpAddr, expected: (u16, u16), ident: u16, seq: u16) {
+    ::tracing::debug!(
+        %addr,
+        ident,
+        seq,
+        expected_ident = expected.0,
+        expected_seq = expected.1,
+        "reply doesn't match the latest probe"
+    );
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn mismatch(addr: IpAddr, expected: (u16, u16), ident: u16, seq: u16) {
+    debug!("Received echo reply from target {}, but sequence_number (expected {} but got {}) and identifier (expected {} but got {}) don't match", addr, expected.1, seq, expected.0, ident);
+}
+
+// another reply from a target that already answered this round
+#[cfg(feature = "tracing")]
+pub fn duplicate(addr: IpAddr, ident: u16, seq: u16) {
+    ::tracing::debug!(%addr, ident, seq, "duplicate reply");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn duplicate(addr: IpAddr, ident: u16, seq: u16) {
+    debug!(
+        "Received duplicate reply {} (identifier {}) from {}",
+        seq, ident, addr
+    );
+}
+
+#[cfg(feature = "tracing")]
+pub fn timeout(addr: IpAddr, attempts: u32) {
+    ::tracing::debug!(%addr, attempts, "no reply within the round");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn timeout(addr: IpAddr, attempts: u32) {
+    trace!("No reply from {} after {} attempts", addr, attempts);
+}
+
+// failure delivering a result to the consumer
+#[cfg(feature = "tracing")]
+pub fn deliver_failed(e: &dyn Display) {
+    ::tracing::error!(error = %e, "failed to deliver result");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn deliver_failed(e: &dyn Display) {
+    error!("Error sending ping result on channel: {}", e);
+}
+
+// failure reading from a listener's socket
+#[cfg(feature = "tracing")]
+pub fn read_failed(e: &dyn Display) {
+    ::tracing::error!(error = %e, "failed to read from socket");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn read_failed(e: &dyn Display) {
+    error!("An error occurred while reading: {}", e);
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt;
+    use PingerBuilder;
+
+    // a writer appending to a shared buffer
+    #[derive(Clone)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events() {
+        let buffer = Buffer(Arc::new(Mutex::new(Vec::new())));
+        let writer = buffer.clone();
+        let subscriber = fmt()
+            .with_max_level(::tracing::Level::TRACE)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let (pinger, _results) = PingerBuilder::new().max_rtt(200).build().unwrap();
+        pinger.add_ipaddr("127.0.0.1");
+        pinger.add_ipaddr("7.7.7.7");
+        ::tracing::subscriber::with_default(subscriber, || pinger.ping_once());
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = |message: &str, addr: &str| {
+            output
+                .lines()
+                .find(|line| line.contains(message) && line.contains(addr))
+                .unwrap_or_else(|| panic!("no {} event for {} in {}", message, addr, output))
+                .to_string()
+        };
+        let sent = line("probe sent", "addr=127.0.0.1");
+        assert!(sent.contains("round{round=1}"));
+        assert!(sent.contains("seq=1"));
+        assert!(sent.contains("ident="));
+        assert!(line("reply received", "addr=127.0.0.1").contains("rtt_us="));
+        assert!(line("no reply within the round", "addr=7.7.7.7").contains("attempts=1"));
+    }
+}
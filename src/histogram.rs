@@ -0,0 +1,594 @@
+// Latency histograms for percentiles, in the style of HdrHistogram: rtts are counted
+// in microsecond buckets that are exact up to 128µs and log-linear above, 64 per
+// power of two, so any recorded value is off by less than 1.6%.  Histograms have
+// the same buckets whatever they've seen, so they merge across targets and pingers
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sink::ResultHandler;
+use socket::Binding;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use PingResult;
+
+// bits of each value kept exactly: values below 2^PRECISION get a bucket each
+const PRECISION: u32 = 7;
+const SUB_BUCKETS: u64 = 1 << PRECISION;
+const HALF: u64 = SUB_BUCKETS / 2;
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = 64 - value.leading_zeros() - PRECISION;
+    let mantissa = value >> shift;
+    (SUB_BUCKETS + (shift as u64 - 1) * HALF + (mantissa - HALF)) as usize
+}
+
+// the lowest and highest values counted in a bucket
+fn bucket_range(index: usize) -> (u64, u64) {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return (index, index);
+    }
+    let shift = (index - SUB_BUCKETS) / HALF + 1;
+    let mantissa = HALF + (index - SUB_BUCKETS) % HALF;
+    let low = mantissa << shift;
+    (low, low + (1 << shift) - 1)
+}
+
+// the count of rtts from low up to high, both inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Bucket {
+    pub low: Duration,
+    pub high: Duration,
+    pub count: u64,
+}
+
+// counts of rtts by log-linear bucket
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RttHistogram {
+    // by bucket index, grown to the highest bucket recorded
+    counts: Vec<u64>,
+    total: u64,
+    // microseconds
+    min: Option<u64>,
+    max: u64,
+    sum: u64,
+}
+
+impl RttHistogram {
+    pub fn new() -> RttHistogram {
+        RttHistogram::default()
+    }
+
+    pub fn record(&mut self, rtt: Duration) {
+        let micros = rtt.as_micros().min(u64::MAX as u128) as u64;
+        let index = bucket_index(micros);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+        self.min = Some(self.min.map_or(micros, |min| min.min(micros)));
+        self.max = self.max.max(micros);
+        self.sum = self.sum.saturating_add(micros);
+    }
+
+    // add another histogram's counts to this one's
+    pub fn merge(&mut self, other: &RttHistogram) {
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, &other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other;
+        }
+        self.total += other.total;
+        self.min = match (self.min, other.min) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.max = self.max.max(other.max);
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+
+    pub fn reset(&mut self) {
+        *self = RttHistogram::default();
+    }
+
+    // rtts recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min.map(Duration::from_micros)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.min.map(|_| Duration::from_micros(self.max))
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        self.min
+            .map(|_| Duration::from_micros(self.sum / self.total))
+    }
+
+    // the rtt at or below which percentile percent of the recorded ones fall, e.g.
+    // 99.9, as the highest value of its bucket.  None if nothing's been recorded
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        let min = self.min?;
+        let percentile = percentile.clamp(0.0, 100.0);
+        let rank = ((percentile / 100.0 * self.total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let high = bucket_range(index).1;
+                return Some(Duration::from_micros(high.clamp(min, self.max)));
+            }
+        }
+        Some(Duration::from_micros(self.max))
+    }
+
+    // the buckets with something counted in them, lowest first
+    pub fn buckets(&self) -> Vec<Bucket> {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(index, &count)| {
+                let (low, high) = bucket_range(index);
+                Bucket {
+                    low: Duration::from_micros(low),
+                    high: Duration::from_micros(high),
+                    count,
+                }
+            })
+            .collect()
+    }
+}
+
+// a target's histograms, one per slice of the window, oldest first
+#[derive(Default)]
+struct Slices {
+    // the slice's start, in slice lengths since the unix epoch
+    slices: VecDeque<(u64, RttHistogram)>,
+}
+
+impl Slices {
+    // drop the slices that slid out of the window
+    fn prune(&mut self, oldest: u64) {
+        while self
+            .slices
+            .front()
+            .is_some_and(|&(start, _)| start < oldest)
+        {
+            self.slices.pop_front();
+        }
+    }
+
+    fn histogram(&self, oldest: u64) -> RttHistogram {
+        let mut histogram = RttHistogram::new();
+        for (_, slice) in self.slices.iter().filter(|&&(start, _)| start >= oldest) {
+            histogram.merge(slice);
+        }
+        histogram
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    // the window and how many slices it's kept in, None to keep everything
+    window: Option<(Duration, u32)>,
+    // whether the window follows the results' times alone rather than the clock
+    replay: bool,
+    targets: BTreeMap<(IpAddr, Binding), Slices>,
+    // the latest reply or round end seen, in microseconds since the unix epoch
+    latest: u64,
+}
+
+impl Shared {
+    fn slice_len(&self) -> Option<u64> {
+        self.window
+            .map(|(window, slices)| (window / slices).as_micros().max(1) as u64)
+    }
+
+    // where the window ends: the latest result time seen or, unless replaying, the
+    // clock if that's later, so the window moves on while replies stop coming
+    fn end(&self) -> u64 {
+        if self.replay {
+            self.latest
+        } else {
+            self.latest.max(micros(SystemTime::now()))
+        }
+    }
+
+    // the first slice still inside the window
+    fn oldest(&self) -> u64 {
+        match (self.window, self.slice_len()) {
+            (Some((_, slices)), Some(slice_len)) => {
+                (self.end() / slice_len).saturating_sub(slices as u64 - 1)
+            }
+            _ => 0,
+        }
+    }
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_micros() as u64
+}
+
+// per-target rtt histograms fed with the replies among PingResults, either through
+// observe or as the pinger's ResultHandler, over all time or a sliding window.
+// Targets are keyed by address and binding, as the pinger keys them.  A window
+// ends at the clock, or at a later reply's receive time; replay() has it follow
+// the replies' receive times and round ends alone, so replayed recordings see the
+// same windows as the live pinger did.  Clones share their histograms
+#[derive(Clone, Default)]
+pub struct LatencyHistograms {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl LatencyHistograms {
+    // histograms of every reply since the last reset
+    pub fn new() -> LatencyHistograms {
+        LatencyHistograms::default()
+    }
+
+    // histograms of the replies within the latest window, which slides a slice at a
+    // time.  More slices slide more smoothly at the cost of memory
+    pub fn sliding(window: Duration, slices: u32) -> LatencyHistograms {
+        let histograms = LatencyHistograms::default();
+        histograms.shared.lock().unwrap().window = Some((window, slices.max(1)));
+        histograms
+    }
+
+    // move the window by the results' times alone, not the clock, for replaying
+    // recordings
+    pub fn replay(self) -> LatencyHistograms {
+        self.shared.lock().unwrap().replay = true;
+        self
+    }
+
+    // count a reply, or move the window on for an Idle target or a RoundComplete
+    pub fn observe(&self, result: &PingResult) {
+        let (addr, binding, reply) = match *result {
+            PingResult::Receive {
+                addr,
+                ref binding,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::Timestamp {
+                addr,
+                ref binding,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                ref binding,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                ref binding,
+                rtt,
+                ref reply,
+                ..
+            } => (addr, binding, Some((rtt, micros(reply.received_at)))),
+            PingResult::Idle {
+                addr, ref binding, ..
+            } => (addr, binding, None),
+            PingResult::RoundComplete {
+                started_at,
+                duration,
+                ..
+            } => {
+                let mut shared = self.shared.lock().unwrap();
+                shared.latest = shared.latest.max(micros(started_at + duration));
+                return;
+            }
+            _ => return,
+        };
+        let key = (addr, binding.clone());
+        let mut shared = self.shared.lock().unwrap();
+        let (rtt, at) = match reply {
+            Some(reply) => reply,
+            None => {
+                let oldest = shared.oldest();
+                if let Some(slices) = shared.targets.get_mut(&key) {
+                    slices.prune(oldest);
+                }
+                return;
+            }
+        };
+        shared.latest = shared.latest.max(at);
+        let slice = shared.slice_len().map_or(0, |slice_len| at / slice_len);
+        let oldest = shared.oldest();
+        let slices = shared.targets.entry(key).or_default();
+        slices.prune(oldest);
+        let slices = &mut slices.slices;
+        // a reply older than the window has nothing to count towards
+        if slice < oldest {
+            return;
+        }
+        match slices
+            .iter_mut()
+            .rev()
+            .find(|&&mut (start, _)| start <= slice)
+        {
+            Some(&mut (start, ref mut histogram)) if start == slice => histogram.record(rtt),
+            _ => {
+                let mut histogram = RttHistogram::new();
+                histogram.record(rtt);
+                let position = slices
+                    .iter()
+                    .position(|&(start, _)| start > slice)
+                    .unwrap_or(slices.len());
+                slices.insert(position, (slice, histogram));
+            }
+        }
+    }
+
+    // a target's histogram over the window, as reached through the given binding
+    pub fn histogram(&self, addr: IpAddr, binding: &Binding) -> Option<RttHistogram> {
+        let shared = self.shared.lock().unwrap();
+        let slices = shared.targets.get(&(addr, binding.clone()))?;
+        Some(slices.histogram(shared.oldest()))
+    }
+
+    // every target's histogram over the window, merged
+    pub fn merged(&self) -> RttHistogram {
+        let shared = self.shared.lock().unwrap();
+        let oldest = shared.oldest();
+        let mut merged = RttHistogram::new();
+        for slices in shared.targets.values() {
+            merged.merge(&slices.histogram(oldest));
+        }
+        merged
+    }
+
+    // the targets seen, with their histograms over the window
+    pub fn histograms(&self) -> BTreeMap<(IpAddr, Binding), RttHistogram> {
+        let shared = self.shared.lock().unwrap();
+        let oldest = shared.oldest();
+        shared
+            .targets
+            .iter()
+            .map(|(target, slices)| (target.clone(), slices.histogram(oldest)))
+            .collect()
+    }
+
+    // empty a target's histograms, starting its window over
+    pub fn reset_target(&self, addr: IpAddr, binding: &Binding) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(slices) = shared.targets.get_mut(&(addr, binding.clone())) {
+            slices.slices.clear();
+        }
+    }
+
+    // empty every target's histograms
+    pub fn reset(&self) {
+        for slices in self.shared.lock().unwrap().targets.values_mut() {
+            slices.slices.clear();
+        }
+    }
+
+    // drop a removed target's histograms
+    pub fn forget(&self, addr: IpAddr, binding: &Binding) {
+        self.shared
+            .lock()
+            .unwrap()
+            .targets
+            .remove(&(addr, binding.clone()));
+    }
+}
+
+impl ResultHandler for LatencyHistograms {
+    fn on_receive(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_round_complete(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ping::PingReply;
+    use target::Probe;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn receive(addr: IpAddr, rtt: Duration, received_at: SystemTime) -> PingResult {
+        PingResult::Receive {
+            addr,
+            rtt,
+            binding: Binding::default(),
+            ttl: Some(64),
+            size: 64,
+            hops: Some(0),
+            route: None,
+            attempts: 1,
+            round_id: 1,
+            reply: PingReply {
+                sequence_number: 1,
+                identifier: 7,
+                size: 64,
+                ttl: Some(64),
+                sent_at: received_at - rtt,
+                received_at,
+                probe: Probe::Echo,
+            },
+        }
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut previous = (0, 0);
+        for index in 1..3000 {
+            let (low, high) = bucket_range(index);
+            assert_eq!(low, previous.1 + 1);
+            assert_eq!(bucket_index(low), index);
+            assert_eq!(bucket_index(high), index);
+            // a bucket spans less than 1.6% of its values
+            assert!((high - low) as f64 <= low as f64 / 63.0);
+            previous = (low, high);
+        }
+        assert!(bucket_index(u64::MAX) < 4000);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = RttHistogram::new();
+        assert_eq!(histogram.percentile(50.0), None);
+        for micros in 1..=10_000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count(), 10_000);
+        assert_eq!(histogram.min(), Some(Duration::from_micros(1)));
+        assert_eq!(histogram.max(), Some(Duration::from_micros(10_000)));
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(5000)));
+        for &(percentile, expected) in [
+            (50.0, 5000.0),
+            (90.0, 9000.0),
+            (99.0, 9900.0),
+            (99.9, 9990.0),
+        ]
+        .iter()
+        {
+            let value = histogram.percentile(percentile).unwrap().as_micros() as f64;
+            assert!(
+                (value - expected).abs() / expected < 0.016,
+                "p{} = {}",
+                percentile,
+                value
+            );
+        }
+        assert_eq!(histogram.percentile(100.0), histogram.max());
+        assert_eq!(histogram.percentile(0.0), histogram.min());
+
+        // merged halves count the same as the whole
+        let (mut low, mut high) = (RttHistogram::new(), RttHistogram::new());
+        for micros in 1..=10_000 {
+            if micros <= 5000 {
+                low.record(Duration::from_micros(micros));
+            } else {
+                high.record(Duration::from_micros(micros));
+            }
+        }
+        high.merge(&low);
+        assert_eq!(high, histogram);
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.iter().map(|b| b.count).sum::<u64>(), 10_000);
+        assert_eq!(buckets[0].low, Duration::from_micros(1));
+        histogram.reset();
+        assert_eq!(histogram.count(), 0);
+        assert!(histogram.buckets().is_empty());
+    }
+
+    #[test]
+    fn test_sliding() {
+        let a = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let b = "192.0.2.2".parse::<IpAddr>().unwrap();
+        let default = Binding::default();
+        let histograms = LatencyHistograms::sliding(Duration::from_secs(60), 6).replay();
+        histograms.observe(&receive(a, Duration::from_millis(100), at(1000)));
+        histograms.observe(&receive(a, Duration::from_millis(1), at(1030)));
+        histograms.observe(&receive(b, Duration::from_millis(2), at(1030)));
+        assert_eq!(histograms.histogram(a, &default).unwrap().count(), 2);
+        assert_eq!(histograms.merged().count(), 3);
+
+        // the first reply slides out of the window
+        histograms.observe(&receive(b, Duration::from_millis(2), at(1065)));
+        let histogram = histograms.histogram(a, &default).unwrap();
+        assert_eq!(histogram.count(), 1);
+        assert_eq!(histogram.max(), Some(Duration::from_millis(1)));
+        assert_eq!(histograms.histograms().len(), 2);
+
+        // and the rest once a round ends past the window, with no replies
+        histograms.observe(&PingResult::RoundComplete {
+            round_id: 2,
+            started_at: at(1200),
+            duration: Duration::from_secs(1),
+            sent: 2,
+            received: 0,
+            idle: 2,
+        });
+        assert_eq!(histograms.merged().count(), 0);
+
+        histograms.observe(&receive(b, Duration::from_millis(2), at(1201)));
+        histograms.reset_target(b, &default);
+        assert_eq!(histograms.histogram(b, &default).unwrap().count(), 0);
+        histograms.observe(&receive(b, Duration::from_millis(2), at(1201)));
+        histograms.reset();
+        assert_eq!(histograms.merged().count(), 0);
+        histograms.forget(a, &default);
+        assert!(histograms.histogram(a, &default).is_none());
+
+        // without a window everything counts
+        let histograms = LatencyHistograms::new();
+        histograms.observe(&receive(a, Duration::from_millis(100), at(1000)));
+        histograms.observe(&receive(a, Duration::from_millis(1), at(100_000)));
+        assert_eq!(histograms.histogram(a, &default).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_sliding_live() {
+        let a = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let default = Binding::default();
+        let eth1 = Binding {
+            source: None,
+            interface: Some("eth1".to_string()),
+        };
+        let now = SystemTime::now();
+        let histograms = LatencyHistograms::sliding(Duration::from_secs(60), 6);
+        histograms.observe(&receive(a, Duration::from_millis(5), now));
+        // the clock ends the window, so replies stop counting once they're old
+        // even when no newer ones come in
+        histograms.observe(&receive(
+            a,
+            Duration::from_millis(9),
+            now - Duration::from_secs(120),
+        ));
+        let histogram = histograms.histogram(a, &default).unwrap();
+        assert_eq!(histogram.count(), 1);
+        assert_eq!(histogram.max(), Some(Duration::from_millis(5)));
+
+        // the same address through another binding is another target
+        let mut reply = receive(a, Duration::from_millis(7), now);
+        if let PingResult::Receive {
+            ref mut binding, ..
+        } = reply
+        {
+            *binding = eth1.clone();
+        }
+        histograms.observe(&reply);
+        assert_eq!(histograms.histograms().len(), 2);
+        assert_eq!(
+            histograms.histogram(a, &eth1).unwrap().max(),
+            Some(Duration::from_millis(7))
+        );
+        assert_eq!(histograms.merged().count(), 2);
+    }
+}
@@ -0,0 +1,315 @@
+// Layer 3 mode, where probes are sent with a hand-crafted ip header instead of one
+// written by the kernel.  Meant for lab use such as testing reverse path filtering,
+// since it allows spoofing the source address, so it's only built with the
+// "layer3" cargo feature
+use ping::{echo_request, echo_requestv6, Ping};
+use pnet::packet::icmpv6;
+use pnet::packet::icmpv6::MutableIcmpv6Packet;
+use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::transport::transport_channel;
+use pnet::transport::TransportChannelType::{Layer3, Layer4};
+use pnet::transport::TransportProtocol::Ipv6;
+use pnet::transport::TransportSender;
+use rand::random;
+use socket::{self, Senders};
+use std::convert::TryFrom;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+// longest ipv4 options field that fits in the 4 bit header length
+const MAX_OPTIONS_LEN: usize = 40;
+
+// ip header fields for a target's probes.  The ttl (hop limit) and tos (traffic
+// class) come from the target's regular options.  An ipv4 source left unset is
+// filled in by the kernel, ipv6 targets need one
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpHeader {
+    pub(crate) source: Option<IpAddr>,
+    pub(crate) identification: Option<u16>,
+    pub(crate) dont_fragment: bool,
+    pub(crate) flow_label: u32,
+    pub(crate) options: Vec<u8>,
+}
+
+impl IpHeader {
+    pub fn new() -> IpHeader {
+        IpHeader::default()
+    }
+
+    // source address written in the header, which doesn't have to be one of ours
+    pub fn source(mut self, source: IpAddr) -> IpHeader {
+        self.source = Some(source);
+        self
+    }
+
+    // ipv4 identification field.  Random per probe when unset
+    pub fn identification(mut self, identification: u16) -> IpHeader {
+        self.identification = Some(identification);
+        self
+    }
+
+    // set the ipv4 don't fragment flag
+    pub fn dont_fragment(mut self, dont_fragment: bool) -> IpHeader {
+        self.dont_fragment = dont_fragment;
+        self
+    }
+
+    // ipv6 flow label (20 bits)
+    pub fn flow_label(mut self, flow_label: u32) -> IpHeader {
+        self.flow_label = flow_label & 0xfffff;
+        self
+    }
+
+    // raw ipv4 options, padded with end of option list bytes to a multiple of 4
+    pub fn options(mut self, options: &[u8]) -> IpHeader {
+        self.options = options.to_vec();
+        self
+    }
+
+    // check the header can be used for probes to addr
+    pub(crate) fn validate(&self, addr: IpAddr) -> Result<(), String> {
+        if let Some(source) = self.source {
+            if source.is_ipv4() != addr.is_ipv4() {
+                return Err(format!(
+                    "source address {} does not match the address family",
+                    source
+                ));
+            }
+        } else if addr.is_ipv6() {
+            return Err("ipv6 headers need a source address".to_string());
+        }
+        if self.options.len() > MAX_OPTIONS_LEN {
+            return Err(format!(
+                "{} bytes of ip options is more than the {} that fit",
+                self.options.len(),
+                MAX_OPTIONS_LEN
+            ));
+        }
+        if addr.is_ipv6() && !self.options.is_empty() {
+            return Err("ip options are only supported for ipv4".to_string());
+        }
+        Ok(())
+    }
+}
+
+// open a header including socket to send layer 3 probes with.  These are send only,
+// replies are still picked up by the regular listeners
+pub fn open_raw(netns: Option<&Path>, ipv6: bool) -> Result<TransportSender, io::Error> {
+    socket::in_netns(netns, move || {
+        // IPPROTO_RAW implies IP_HDRINCL (IPV6_HDRINCL) and never receives anything
+        let protocol = if ipv6 {
+            Layer4(Ipv6(IpNextHeaderProtocol(255)))
+        } else {
+            Layer3(IpNextHeaderProtocol(255))
+        };
+        transport_channel(4096, protocol).map(|(tx, _)| tx)
+    })
+}
+
+// send the next echo request to a target wrapped in its hand-crafted header
+pub fn send_echo(senders: &mut Senders, ping: &mut Ping, size: usize) -> Result<usize, io::Error> {
+    let mut header = match ping.header {
+        Some(ref header) => header.clone(),
+        None => return Err(io::Error::other("target has no ip header")),
+    };
+    if let (true, Some(record)) = (header.options.is_empty(), ping.record) {
+        header.options = record.option_bytes();
+    }
+    let missing = || io::Error::new(io::ErrorKind::NotFound, "no layer 3 socket open");
+    let (sent, packet) = match ping.get_addr() {
+        IpAddr::V4(destination) => {
+            let packet = build_ipv4(
+                &header,
+                destination,
+                ping.ttl,
+                ping.tos,
+                &echo_request(ping, size),
+            )?;
+            let tx = senders.raw.as_mut().ok_or_else(missing)?;
+            let sent = tx.send_to(Ipv4Packet::new(&packet[..]).unwrap(), ping.get_addr())?;
+            (sent, packet)
+        }
+        IpAddr::V6(destination) => {
+            let packet = build_ipv6(
+                &header,
+                destination,
+                ping.ttl,
+                ping.tos,
+                &echo_requestv6(ping, size),
+            )?;
+            let tx = senders.rawv6.as_mut().ok_or_else(missing)?;
+            let sent = tx.send_to(Ipv6Packet::new(&packet[..]).unwrap(), ping.get_addr())?;
+            (sent, packet)
+        }
+    };
+    // the header's already there, the capture gets the packet as sent
+    if let Some(ref capture) = senders.capture {
+        capture.lock().unwrap().packet(&packet);
+    }
+    Ok(sent)
+}
+
+// wrap an icmp message in an ipv4 header
+pub fn build_ipv4(
+    header: &IpHeader,
+    destination: Ipv4Addr,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    icmp: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let header_len = 20 + header.options.len().div_ceil(4) * 4;
+    let total_length = u16::try_from(header_len + icmp.len()).map_err(|_| too_long())?;
+    let mut vec: Vec<u8> = vec![0; header_len + icmp.len()];
+    vec[20..20 + header.options.len()].copy_from_slice(&header.options);
+    vec[header_len..].copy_from_slice(icmp);
+
+    let source = match header.source {
+        Some(IpAddr::V4(source)) => source,
+        _ => Ipv4Addr::UNSPECIFIED,
+    };
+    let tos = tos.unwrap_or(0);
+    let mut ip_packet = MutableIpv4Packet::new(&mut vec[..]).unwrap();
+    ip_packet.set_version(4);
+    ip_packet.set_header_length((header_len / 4) as u8);
+    ip_packet.set_dscp(tos >> 2);
+    ip_packet.set_ecn(tos & 0x3);
+    ip_packet.set_total_length(total_length);
+    ip_packet.set_identification(header.identification.unwrap_or_else(random::<u16>));
+    if header.dont_fragment {
+        ip_packet.set_flags(Ipv4Flags::DontFragment);
+    }
+    ip_packet.set_ttl(ttl.unwrap_or(64));
+    ip_packet.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+    ip_packet.set_source(source);
+    ip_packet.set_destination(destination);
+    let csum = ipv4::checksum(&ip_packet.to_immutable());
+    ip_packet.set_checksum(csum);
+    Ok(vec)
+}
+
+// wrap an icmpv6 message in an ipv6 header.  The kernel doesn't fill in the icmpv6
+// checksum for header including sockets, so it's calculated here
+pub fn build_ipv6(
+    header: &IpHeader,
+    destination: Ipv6Addr,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    icmp: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let payload_length = u16::try_from(icmp.len()).map_err(|_| too_long())?;
+    let source = match header.source {
+        Some(IpAddr::V6(source)) => source,
+        _ => Ipv6Addr::UNSPECIFIED,
+    };
+    let mut vec: Vec<u8> = vec![0; 40 + icmp.len()];
+    vec[40..].copy_from_slice(icmp);
+    {
+        let mut icmp_packet = MutableIcmpv6Packet::new(&mut vec[40..]).unwrap();
+        let csum = icmpv6::checksum(&icmp_packet.to_immutable(), &source, &destination);
+        icmp_packet.set_checksum(csum);
+    }
+
+    let mut ip_packet = MutableIpv6Packet::new(&mut vec[..]).unwrap();
+    ip_packet.set_version(6);
+    ip_packet.set_traffic_class(tos.unwrap_or(0));
+    ip_packet.set_flow_label(header.flow_label);
+    ip_packet.set_payload_length(payload_length);
+    ip_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+    ip_packet.set_hop_limit(ttl.unwrap_or(64));
+    ip_packet.set_source(source);
+    ip_packet.set_destination(destination);
+    Ok(vec)
+}
+
+fn too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "packet is too long for the ip length field",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pnet::packet::icmpv6::Icmpv6Packet;
+
+    #[test]
+    fn test_build_ipv4() {
+        let header = IpHeader::new()
+            .source("10.0.0.1".parse::<IpAddr>().unwrap())
+            .identification(0x1234)
+            .dont_fragment(true)
+            .options(&[1, 1, 1]);
+        let packet = build_ipv4(
+            &header,
+            "10.0.0.2".parse::<Ipv4Addr>().unwrap(),
+            Some(7),
+            Some(46 << 2),
+            &[8, 0, 0, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+        let ip_packet = Ipv4Packet::new(&packet[..]).unwrap();
+        assert_eq!(ip_packet.get_header_length(), 6);
+        assert_eq!(ip_packet.get_total_length(), 32);
+        assert_eq!(ip_packet.get_identification(), 0x1234);
+        assert_eq!(ip_packet.get_flags(), Ipv4Flags::DontFragment);
+        assert_eq!(ip_packet.get_ttl(), 7);
+        assert_eq!(ip_packet.get_dscp(), 46);
+        assert_eq!(
+            ip_packet.get_source(),
+            "10.0.0.1".parse::<Ipv4Addr>().unwrap()
+        );
+        assert_eq!(&packet[20..24], &[1, 1, 1, 0]);
+        assert_eq!(ip_packet.get_checksum(), ipv4::checksum(&ip_packet));
+    }
+
+    #[test]
+    fn test_build_ipv6() {
+        let source = "fe80::1".parse::<Ipv6Addr>().unwrap();
+        let destination = "fe80::2".parse::<Ipv6Addr>().unwrap();
+        let header = IpHeader::new()
+            .source(IpAddr::V6(source))
+            .flow_label(0xabcde);
+        let packet = build_ipv6(
+            &header,
+            destination,
+            None,
+            None,
+            &[128, 0, 0, 0, 0, 1, 0, 1],
+        )
+        .unwrap();
+        let ip_packet = Ipv6Packet::new(&packet[..]).unwrap();
+        assert_eq!(ip_packet.get_flow_label(), 0xabcde);
+        assert_eq!(ip_packet.get_payload_length(), 8);
+        assert_eq!(ip_packet.get_hop_limit(), 64);
+        let icmp_packet = Icmpv6Packet::new(&packet[40..]).unwrap();
+        assert_eq!(
+            icmp_packet.get_checksum(),
+            icmpv6::checksum(&icmp_packet, &source, &destination)
+        );
+    }
+
+    #[test]
+    fn test_build_too_long() {
+        let icmp = vec![0; 65536 - 20];
+        let v4 = "10.0.0.2".parse::<Ipv4Addr>().unwrap();
+        assert!(build_ipv4(&IpHeader::new(), v4, None, None, &icmp[..icmp.len() - 1]).is_ok());
+        assert!(build_ipv4(&IpHeader::new(), v4, None, None, &icmp).is_err());
+        let header = IpHeader::new().source("::1".parse::<IpAddr>().unwrap());
+        let v6 = "::2".parse::<Ipv6Addr>().unwrap();
+        assert!(build_ipv6(&header, v6, None, None, &vec![0; 65536]).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let v4 = "10.0.0.2".parse::<IpAddr>().unwrap();
+        let v6 = "::1".parse::<IpAddr>().unwrap();
+        assert!(IpHeader::new().validate(v4).is_ok());
+        assert!(IpHeader::new().validate(v6).is_err());
+        assert!(IpHeader::new().source(v4).validate(v6).is_err());
+        assert!(IpHeader::new().options(&[1; 41]).validate(v4).is_err());
+    }
+}
@@ -1,37 +1,402 @@
+extern crate libc;
+#[cfg(feature = "opentelemetry")]
+pub extern crate opentelemetry;
+#[cfg(all(test, feature = "opentelemetry"))]
+extern crate opentelemetry_sdk;
 extern crate pnet;
 extern crate pnet_macros_support;
+extern crate pnet_sys;
+#[cfg(feature = "prometheus")]
+pub extern crate prometheus;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(all(test, feature = "tracing"))]
+extern crate tracing_subscriber;
 #[macro_use]
 extern crate log;
 extern crate rand;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
 
+mod capture;
+mod events;
+mod histogram;
+#[cfg(feature = "layer3")]
+mod layer3;
+mod neighbor;
+mod nodeinfo;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+#[cfg(feature = "serde")]
+mod output;
 mod ping;
+#[cfg(feature = "prometheus")]
+mod prom;
+mod record;
+mod route;
+mod sink;
+mod socket;
+mod state;
+mod stats;
+mod sweep;
+mod target;
+mod tcp;
+mod timestamp;
+mod udp;
 
-use ping::{send_pings, Ping, ReceivedPing};
+pub use capture::CaptureOptions;
+pub use histogram::{Bucket, LatencyHistograms, RttHistogram};
+#[cfg(feature = "layer3")]
+pub use layer3::IpHeader;
+#[cfg(feature = "opentelemetry")]
+pub use otel::OtelRecorder;
+#[cfg(feature = "serde")]
+pub use output::{CsvWriter, NdjsonWriter};
+pub use ping::PingReply;
+pub use pnet::util::MacAddr;
+#[cfg(feature = "prometheus")]
+pub use prom::{serve_metrics, PingCollector};
+pub use record::{Recorder, Replay, RECORDING_VERSION};
+pub use route::{RecordMode, RecordedRoute};
+pub use sink::{BoundedReceiver, OverflowPolicy, ResultHandler};
+pub use socket::Binding;
+pub use state::{StateThresholds, TargetState};
+pub use stats::{Statistics, StatsSnapshot, TargetStats};
+pub use sweep::{hosts, SweepOptions, SweepProgress};
+pub use target::{Probe, TargetOptions};
+pub use timestamp::TimestampReply;
+
+use capture::Capture;
+use ping::{send_pings, Ping, ReceivedPing, RetryPolicy, TargetKey};
+use pnet::datalink::DataLinkReceiver;
 use pnet::packet::icmp::echo_reply::EchoReplyPacket as IcmpEchoReplyPacket;
+use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::icmpv6::echo_reply::EchoReplyPacket as Icmpv6EchoReplyPacket;
-use pnet::packet::ip::IpNextHeaderProtocols;
-use pnet::packet::Packet;
+use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::{icmp, icmpv6};
-use pnet::transport::transport_channel;
-use pnet::transport::TransportChannelType::Layer4;
-use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
-use pnet::transport::{icmp_packet_iter, icmpv6_packet_iter};
-use pnet::transport::{TransportReceiver, TransportSender};
-use std::collections::BTreeMap;
+use pnet::transport::TransportReceiver;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sink::ResultSink;
+use socket::{Channel, Senders};
+use state::StateTracker;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 // result type returned by fastping_rs::Pinger::new()
 pub type NewPingerResult = Result<(Pinger, Receiver<PingResult>), String>;
 
-// ping result type.  Idle represents pings that have not received a repsonse within the max_rtt.
-// Receive represents pings which have received a repsonse
+// ping result type.  Idle represents pings that have not received a repsonse within the max_rtt,
+// or after all retries, along with the kind of probe sent.
+// Receive represents pings which have received a repsonse, along with the reply's ttl
+// (hop limit), its size in bytes, the number of hops inferred from the ttl and, for
+// probes sent with a RecordMode, the recorded route.  Both report the binding
+// (source address and interface) the probe was sent with.  Timestamp represents replies
+// to Probe::Timestamp targets, with the times reported by the target.  Neighbor
+// represents ARP or Neighbor Advertisement replies to Probe::Neighbor targets, with
+// the target's MAC address.  NodeInfo represents Node Information replies to
+// Probe::NodeInfo targets, with the names the target reported.  Discovered represents
+// each responder to a probe of a target added with TargetOptions::discover, along
+// with its names for Probe::NodeInfo.  All but Discovered report the attempts, the
+// number of probes sent to the target that round, and all five carry a PingReply
+// with the reply's sequence number, identifier, size, ttl and wall clock times.
+// StateChanged follows the result that moved a target tracked with StateThresholds
+// from one TargetState to another.  Every result carries the round_id of the round
+// it belongs to, and RoundComplete closes each round after its Idle results with the
+// time it started, how long it took, the probes sent (retries included) and how many
+// targets answered or were idle
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(tag = "type"))]
 pub enum PingResult {
-    Idle { addr: IpAddr },
-    Receive { addr: IpAddr, rtt: Duration },
+    Idle {
+        addr: IpAddr,
+        binding: Binding,
+        attempts: u32,
+        round_id: u64,
+        probe: Probe,
+    },
+    Receive {
+        addr: IpAddr,
+        rtt: Duration,
+        binding: Binding,
+        ttl: Option<u8>,
+        size: usize,
+        hops: Option<u8>,
+        route: Option<RecordedRoute>,
+        attempts: u32,
+        round_id: u64,
+        reply: PingReply,
+    },
+    Timestamp {
+        addr: IpAddr,
+        rtt: Duration,
+        binding: Binding,
+        timestamps: TimestampReply,
+        attempts: u32,
+        round_id: u64,
+        reply: PingReply,
+    },
+    Neighbor {
+        addr: IpAddr,
+        rtt: Duration,
+        binding: Binding,
+        mac: MacAddr,
+        attempts: u32,
+        round_id: u64,
+        reply: PingReply,
+    },
+    NodeInfo {
+        addr: IpAddr,
+        rtt: Duration,
+        binding: Binding,
+        names: Vec<String>,
+        attempts: u32,
+        round_id: u64,
+        reply: PingReply,
+    },
+    Discovered {
+        addr: IpAddr,
+        rtt: Duration,
+        binding: Binding,
+        names: Vec<String>,
+        round_id: u64,
+        reply: PingReply,
+    },
+    StateChanged {
+        addr: IpAddr,
+        binding: Binding,
+        from: TargetState,
+        to: TargetState,
+        round_id: u64,
+        probe: Probe,
+    },
+    RoundComplete {
+        round_id: u64,
+        started_at: SystemTime,
+        duration: Duration,
+        sent: usize,
+        received: usize,
+        idle: usize,
+    },
+}
+
+// builder for a Pinger with settings beyond the max_rtt and size taken by Pinger::new()
+#[derive(Clone, Debug)]
+pub struct PingerBuilder {
+    max_rtt: Duration,
+    size: usize,
+    binding: Binding,
+    bindingv6: Binding,
+    netns: Option<PathBuf>,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    record: Option<RecordMode>,
+    retry: RetryPolicy,
+    states: Option<StateThresholds>,
+    capture: Option<CaptureOptions>,
+}
+
+impl Default for PingerBuilder {
+    fn default() -> PingerBuilder {
+        PingerBuilder {
+            max_rtt: Duration::from_millis(2000),
+            size: 16,
+            binding: Binding::default(),
+            bindingv6: Binding::default(),
+            netns: None,
+            ttl: None,
+            tos: None,
+            record: None,
+            retry: RetryPolicy::default(),
+            states: None,
+            capture: None,
+        }
+    }
+}
+
+impl PingerBuilder {
+    pub fn new() -> PingerBuilder {
+        PingerBuilder::default()
+    }
+
+    // idle timeout in milliseconds.  Default is 2000
+    pub fn max_rtt(mut self, max_rtt: u64) -> PingerBuilder {
+        self.max_rtt = Duration::from_millis(max_rtt);
+        self
+    }
+
+    // size in bytes of the icmp messages to send, their 8 byte header included.
+    // Default is 16 bytes
+    pub fn size(mut self, size: usize) -> PingerBuilder {
+        self.size = size;
+        self
+    }
+
+    // default source address for targets of the same address family.  Call once
+    // with an ipv4 and once with an ipv6 address to set both
+    pub fn source(mut self, source: IpAddr) -> PingerBuilder {
+        if source.is_ipv4() {
+            self.binding.source = Some(source);
+        } else {
+            self.bindingv6.source = Some(source);
+        }
+        self
+    }
+
+    // default interface to send probes out of (SO_BINDTODEVICE)
+    pub fn interface(mut self, interface: &str) -> PingerBuilder {
+        self.binding.interface = Some(interface.to_string());
+        self.bindingv6.interface = Some(interface.to_string());
+        self
+    }
+
+    // default VRF to send probes within.  On linux this binds to the VRF master
+    // device, so it replaces any interface set previously
+    pub fn vrf(self, vrf: &str) -> PingerBuilder {
+        self.interface(vrf)
+    }
+
+    // default ip ttl (ipv6 hop limit) of probes.  Unset uses the kernel default
+    pub fn ttl(mut self, ttl: u8) -> PingerBuilder {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // default ip tos (ipv6 traffic class) byte of probes: the DSCP in the upper
+    // six bits and ECN in the lower two, e.g. 46 << 2 for EF.  Unset uses 0
+    pub fn tos(mut self, tos: u8) -> PingerBuilder {
+        self.tos = Some(tos);
+        self
+    }
+
+    // send ipv4 probes with a Record Route or Timestamp option, so replies report the
+    // hops on the forward and return paths.  Ignored for ipv6 targets
+    pub fn record(mut self, record: RecordMode) -> PingerBuilder {
+        self.record = Some(record);
+        self
+    }
+
+    // resend to targets that haven't answered up to this many times within a round
+    // before reporting them Idle.  Default is 0
+    pub fn retries(mut self, retries: u32) -> PingerBuilder {
+        self.retry.retries = retries;
+        self
+    }
+
+    // milliseconds to wait for a reply to the first probe of a round before
+    // retrying.  Default is the max_rtt
+    pub fn retry_timeout(mut self, timeout: u64) -> PingerBuilder {
+        self.retry.timeout = Some(Duration::from_millis(timeout));
+        self
+    }
+
+    // factor the wait grows by with each retry.  Default is 1.5
+    pub fn backoff(mut self, backoff: f64) -> PingerBuilder {
+        self.retry.backoff = backoff;
+        self
+    }
+
+    // track every target's TargetState and report changes as
+    // PingResult::StateChanged.  Unset reports none
+    pub fn states(mut self, thresholds: StateThresholds) -> PingerBuilder {
+        self.states = Some(thresholds);
+        self
+    }
+
+    // copy every echo request sent and every icmp message the listeners read to a
+    // pcap file, for a look at the packets in Wireshark
+    pub fn capture(mut self, options: CaptureOptions) -> PingerBuilder {
+        self.capture = Some(options);
+        self
+    }
+
+    // create the pinger's sockets inside the network namespace at the given path
+    // (e.g. /var/run/netns/<name>).  Only socket creation happens in the namespace,
+    // the pinger's threads keep running in the caller's namespace
+    pub fn netns<P: AsRef<Path>>(mut self, netns: P) -> PingerBuilder {
+        self.netns = Some(netns.as_ref().to_path_buf());
+        self
+    }
+
+    // open the sockets, start the icmp and icmpv6 listeners and return the
+    // pinger along with the channel results are delivered on
+    pub fn build(self) -> NewPingerResult {
+        let (sender, receiver) = channel();
+        let pinger = self.build_with_sink(ResultSink::Channel(sender))?;
+        Ok((pinger, receiver))
+    }
+
+    // like build, but deliver results on a queue holding at most capacity of them,
+    // applying policy once it's full, so a consumer that falls behind can't grow
+    // memory use without bound
+    pub fn build_bounded(
+        self,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> Result<(Pinger, BoundedReceiver), String> {
+        let (sender, receiver) = sink::bounded(capacity, policy);
+        let pinger = self.build_with_sink(ResultSink::Bounded(sender))?;
+        Ok((pinger, receiver))
+    }
+
+    // like build, but deliver results to the handler's callbacks instead of a channel
+    pub fn build_with_handler<H: ResultHandler + 'static>(
+        self,
+        handler: H,
+    ) -> Result<Pinger, String> {
+        self.build_with_sink(ResultSink::Handler(Arc::new(Mutex::new(Box::new(handler)))))
+    }
+
+    fn build_with_sink(self, sender: ResultSink) -> Result<Pinger, String> {
+        let (thread_tx, thread_rx) = channel();
+        let capture = match self.capture {
+            Some(options) => Some(Arc::new(Mutex::new(
+                Capture::create(options).map_err(|e| e.to_string())?,
+            ))),
+            None => None,
+        };
+
+        let pinger = Pinger {
+            max_rtt: Arc::new(self.max_rtt),
+            targets: Arc::new(Mutex::new(BTreeMap::new())),
+            size: self.size,
+            binding: self.binding,
+            bindingv6: self.bindingv6,
+            netns: self.netns.clone(),
+            ttl: self.ttl,
+            tos: self.tos,
+            record: self.record,
+            retry: self.retry,
+            round: Arc::new(Mutex::new(0)),
+            states: self.states,
+            results_sender: sender,
+            senders: Arc::new(Mutex::new(Senders {
+                netns: self.netns,
+                capture: capture.clone(),
+                ..Senders::default()
+            })),
+            capture,
+            thread_rx: Arc::new(Mutex::new(thread_rx)),
+            thread_tx,
+            rounds: Arc::new(Mutex::new(())),
+            stop: Arc::new(Mutex::new(false)),
+        };
+
+        pinger.open_listener(false)?;
+        pinger.open_listener(true)?;
+        let binding = pinger.binding.clone();
+        pinger.open_channel(false, &binding)?;
+        let bindingv6 = pinger.bindingv6.clone();
+        pinger.open_channel(true, &bindingv6)?;
+        Ok(pinger)
+    }
 }
 
 pub struct Pinger {
@@ -39,26 +404,47 @@ pub struct Pinger {
     // the library calls an idle callback function.  Default is 2000
     max_rtt: Arc<Duration>,
 
-    // map of addresses to ping on each run
-    targets: Arc<Mutex<BTreeMap<IpAddr, Ping>>>,
+    // map of addresses (and the binding to reach them through) to ping on each run
+    targets: Arc<Mutex<BTreeMap<TargetKey, Ping>>>,
 
     // Size in bytes of the payload to send.  Default is 16 bytes
     size: usize,
 
-    // sender end of the channel for piping results to client
-    results_sender: Sender<PingResult>,
+    // default binding for ipv4 targets
+    binding: Binding,
+
+    // default binding for ipv6 targets
+    bindingv6: Binding,
+
+    // network namespace sockets are created in, if not the caller's
+    netns: Option<PathBuf>,
 
-    // sender end of libpnet icmp v4 transport channel
-    tx: Arc<Mutex<TransportSender>>,
+    // default ttl (hop limit) of probes, None for the kernel default
+    ttl: Option<u8>,
 
-    // receiver end of libpnet icmp v4 transport channel
-    rx: Arc<Mutex<TransportReceiver>>,
+    // default tos (traffic class) of probes, None for the kernel default
+    tos: Option<u8>,
 
-    // sender end of libpnet icmp v6 transport channel
-    txv6: Arc<Mutex<TransportSender>>,
+    // default ipv4 record route or timestamp option of probes
+    record: Option<RecordMode>,
 
-    // receiver end of libpnet icmp v6 transport channel
-    rxv6: Arc<Mutex<TransportReceiver>>,
+    // when to resend to targets that haven't answered within a round
+    retry: RetryPolicy,
+
+    // id of the latest round, counting up from 1
+    round: Arc<Mutex<u64>>,
+
+    // default thresholds targets' states are tracked with, None to not track them
+    states: Option<StateThresholds>,
+
+    // channel, queue or handler results are piped to the client through
+    results_sender: ResultSink,
+
+    // sender ends of libpnet icmp v4 and v6 transport channels, one per binding
+    senders: Arc<Mutex<Senders>>,
+
+    // packet capture the listeners copy what they read to, if any
+    capture: Option<Arc<Mutex<Capture>>>,
 
     // sender for internal result passing beween threads
     thread_tx: Sender<ReceivedPing>,
@@ -66,8 +452,9 @@ pub struct Pinger {
     // receiver for internal result passing beween threads
     thread_rx: Arc<Mutex<Receiver<ReceivedPing>>>,
 
-    // timer for tracking round trip times
-    timer: Arc<RwLock<Instant>>,
+    // held by whatever runs rounds through the pinger's sockets, so a sweep can't
+    // read the replies meant for run_pinger or ping_once
+    rounds: Arc<Mutex<()>>,
 
     // flag to stop pinging
     stop: Arc<Mutex<bool>>,
@@ -76,70 +463,187 @@ pub struct Pinger {
 impl Pinger {
     // initialize the pinger and start the icmp and icmpv6 listeners
     pub fn new(_max_rtt: Option<u64>, _size: Option<usize>) -> NewPingerResult {
-        let targets = BTreeMap::new();
-        let (sender, receiver) = channel();
+        let mut builder = PingerBuilder::new();
+        if let Some(rtt_value) = _max_rtt {
+            builder = builder.max_rtt(rtt_value);
+        }
+        if let Some(size_value) = _size {
+            builder = builder.size(size_value);
+        }
+        builder.build()
+    }
 
-        let protocol = Layer4(Ipv4(IpNextHeaderProtocols::Icmp));
-        let (tx, rx) = match transport_channel(4096, protocol) {
-            Ok((tx, rx)) => (tx, rx),
-            Err(e) => return Err(e.to_string()),
-        };
+    // add either an ipv4 or ipv6 target address for pinging
+    pub fn add_ipaddr(&self, ipaddr: &str) {
+        self.add_ipaddr_with(ipaddr, TargetOptions::new())
+    }
 
-        let protocolv6 = Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6));
-        let (txv6, rxv6) = match transport_channel(4096, protocolv6) {
-            Ok((txv6, rxv6)) => (txv6, rxv6),
-            Err(e) => return Err(e.to_string()),
+    // add either an ipv4 or ipv6 target address for pinging with per-target options.
+    // Adding the same address with a different source or interface pings it via each,
+    // adding it again via the same one with different probe options is an error
+    pub fn add_ipaddr_with(&self, ipaddr: &str, options: TargetOptions) {
+        let addr = ipaddr.parse::<IpAddr>();
+        match addr {
+            Ok(valid_addr) => match self.new_target(valid_addr, options) {
+                Ok((key, mut new_ping)) => {
+                    let mut targets = self.targets.lock().unwrap();
+                    let replaced = match targets.get(&key) {
+                        Some(ping) if !ping.same_options(&new_ping) => {
+                            drop(targets);
+                            self.release(&key.1);
+                            error!(
+                                "Error adding ip address {}. Error: already added via {:?} with other options",
+                                ipaddr, key.1
+                            );
+                            return;
+                        }
+                        Some(_) => true,
+                        None => false,
+                    };
+                    debug!("Address added {} via {:?}", key.0, key.1);
+                    ping::unique_identifier(&targets, &key, &mut new_ping);
+                    targets.insert(key.clone(), new_ping);
+                    drop(targets);
+                    // the target replaced held the binding too
+                    if replaced {
+                        self.release(&key.1);
+                    }
+                }
+                Err(e) => {
+                    error!("Error adding ip address {}. Error: {}", ipaddr, e);
+                }
+            },
+            Err(e) => {
+                error!("Error adding ip address {}. Error: {}", ipaddr, e);
+            }
         };
+    }
 
-        let (thread_tx, thread_rx) = channel();
-
-        let mut pinger = Pinger {
-            max_rtt: Arc::new(Duration::from_millis(2000)),
-            targets: Arc::new(Mutex::new(targets)),
-            size: _size.unwrap_or(16),
-            results_sender: sender,
-            tx: Arc::new(Mutex::new(tx)),
-            rx: Arc::new(Mutex::new(rx)),
-            txv6: Arc::new(Mutex::new(txv6)),
-            rxv6: Arc::new(Mutex::new(rxv6)),
-            thread_rx: Arc::new(Mutex::new(thread_rx)),
-            thread_tx,
-            timer: Arc::new(RwLock::new(Instant::now())),
-            stop: Arc::new(Mutex::new(false)),
+    // validate a target's options against its address and build its ping, opening
+    // any sockets it needs along the way
+    fn new_target(
+        &self,
+        addr: IpAddr,
+        options: TargetOptions,
+    ) -> Result<(TargetKey, Ping), String> {
+        let (ttl, tos) = (options.ttl.or(self.ttl), options.tos.or(self.tos));
+        let probe = options.probe;
+        if probe == Probe::Timestamp && addr.is_ipv6() {
+            return Err("timestamp probes are ipv4 only".to_string());
+        }
+        if probe == Probe::NodeInfo && addr.is_ipv4() {
+            return Err("node information probes are ipv6 only".to_string());
+        }
+        let discover = options.discover;
+        let states = options.states.or(self.states);
+        if discover {
+            if probe != Probe::Echo && probe != Probe::NodeInfo {
+                return Err("discovery needs echo or node information probes".to_string());
+            }
+            if addr.is_ipv6() && !addr.is_multicast() {
+                return Err("ipv6 discovery needs a multicast address".to_string());
+            }
+        }
+        let record = match options.record {
+            Some(_) if addr.is_ipv6() => {
+                return Err("record route and timestamp options are ipv4 only".to_string());
+            }
+            Some(record) => Some(record),
+            None if addr.is_ipv4() => self.record,
+            None => None,
         };
-        if let Some(rtt_value) = _max_rtt {
-            pinger.max_rtt = Arc::new(Duration::from_millis(rtt_value));
+        #[cfg(feature = "layer3")]
+        let header = options.header.clone();
+        #[cfg(feature = "layer3")]
+        {
+            if probe != Probe::Echo && header.is_some() {
+                return Err("ip headers only apply to icmp probes".to_string());
+            }
         }
-        if let Some(size_value) = _size {
-            pinger.size = size_value;
+        let layer3 = options.layer3();
+        let binding = self.target_binding(addr, options)?;
+        let mut new_ping = Ping::new(addr);
+        new_ping.ttl = ttl;
+        new_ping.tos = tos;
+        new_ping.record = record;
+        new_ping.probe = probe;
+        new_ping.discover = discover;
+        // discovery targets answer for others, they have no state of their own
+        if !discover {
+            new_ping.state = states.map(StateTracker::new);
+        }
+        #[cfg(feature = "layer3")]
+        {
+            new_ping.header = header;
+        }
+        if let Err(e) = self.open_target(&binding, &mut new_ping) {
+            self.release(&binding);
+            return Err(e);
         }
+        Ok(((addr, binding, layer3), new_ping))
+    }
 
-        pinger.start_listener();
-        Ok((pinger, receiver))
+    // open what a new target needs beyond its binding's sockets
+    fn open_target(&self, binding: &Binding, ping: &mut Ping) -> Result<(), String> {
+        let addr = ping.get_addr();
+        if ping.discover && addr.is_ipv4() {
+            self.enable_broadcast(binding)?;
+        }
+        if let Probe::Udp(_) = ping.probe {
+            let udp_socket = udp::open_socket(
+                self.netns.as_deref(),
+                addr.is_ipv6(),
+                binding,
+                ping.ttl,
+                ping.tos,
+            )
+            .map_err(|e| e.to_string())?;
+            let local_addr = udp_socket.local_addr().map_err(|e| e.to_string())?;
+            ping.set_identifier(local_addr.port());
+            ping.udp = Some(udp_socket);
+        }
+        Ok(())
     }
 
-    // add either an ipv4 or ipv6 target address for pinging
-    pub fn add_ipaddr(&self, ipaddr: &str) {
+    // remove a previously added ipv4 or ipv6 target address, whatever its binding
+    pub fn remove_ipaddr(&self, ipaddr: &str) {
         let addr = ipaddr.parse::<IpAddr>();
         match addr {
             Ok(valid_addr) => {
-                debug!("Address added {}", valid_addr);
-                let new_ping = Ping::new(valid_addr);
-                self.targets.lock().unwrap().insert(valid_addr, new_ping);
+                debug!("Address removed {}", valid_addr);
+                let mut removed = Vec::new();
+                self.targets
+                    .lock()
+                    .unwrap()
+                    .retain(|&(addr, ref binding, _), _| {
+                        if addr == valid_addr {
+                            removed.push(binding.clone());
+                        }
+                        addr != valid_addr
+                    });
+                for binding in removed {
+                    self.release(&binding);
+                }
             }
             Err(e) => {
-                error!("Error adding ip address {}. Error: {}", ipaddr, e);
+                error!("Error removing ip address {}. Error: {}", ipaddr, e);
             }
         };
     }
 
-    // remove a previously added ipv4 or ipv6 target address
-    pub fn remove_ipaddr(&self, ipaddr: &str) {
+    // remove a target previously added with add_ipaddr_with and the same options
+    pub fn remove_ipaddr_with(&self, ipaddr: &str, options: TargetOptions) {
         let addr = ipaddr.parse::<IpAddr>();
         match addr {
             Ok(valid_addr) => {
-                debug!("Address removed {}", valid_addr);
-                self.targets.lock().unwrap().remove(&valid_addr);
+                let layer3 = options.layer3();
+                let binding = self.resolve_binding(valid_addr, options);
+                debug!("Address removed {} via {:?}", valid_addr, binding);
+                let key = (valid_addr, binding.clone(), layer3);
+                let removed = self.targets.lock().unwrap().remove(&key);
+                if removed.is_some() {
+                    self.release(&binding);
+                }
             }
             Err(e) => {
                 error!("Error removing ip address {}. Error: {}", ipaddr, e);
@@ -165,14 +669,16 @@ impl Pinger {
 
     // run pinger either once or continuously
     fn run_pings(&self, run_once: bool) {
+        let thread_tx = self.thread_tx.clone();
         let thread_rx = self.thread_rx.clone();
-        let tx = self.tx.clone();
-        let txv6 = self.txv6.clone();
+        let senders = self.senders.clone();
         let results_sender = self.results_sender.clone();
         let stop = self.stop.clone();
         let targets = self.targets.clone();
-        let timer = self.timer.clone();
+        let rounds = self.rounds.clone();
         let max_rtt = self.max_rtt.clone();
+        let retry = self.retry;
+        let round = self.round.clone();
         let size = self.size;
 
         {
@@ -186,126 +692,525 @@ impl Pinger {
         }
 
         if run_once {
+            let _rounds = rounds.lock().unwrap();
             send_pings(
                 size,
-                timer,
                 stop,
                 results_sender,
+                thread_tx,
                 thread_rx,
-                tx,
-                txv6,
+                senders,
                 targets,
                 max_rtt,
+                Duration::ZERO,
+                retry,
+                round,
             );
         } else {
             thread::spawn(move || {
+                let _rounds = rounds.lock().unwrap();
                 send_pings(
                     size,
-                    timer,
                     stop,
                     results_sender,
+                    thread_tx,
                     thread_rx,
-                    tx,
-                    txv6,
+                    senders,
                     targets,
                     max_rtt,
+                    Duration::ZERO,
+                    retry,
+                    round,
                 );
             });
         }
     }
 
-    fn start_listener(&self) {
-        // start icmp listeners in the background and use internal channels for results
+    // probe every host address in a network (e.g. "192.0.2.0/24") and return the ones
+    // that answered with their round trip times.  Runs rounds of up to the sweep's
+    // parallelism through the pinger's own sockets, so it fails while run_pinger or
+    // ping_once is running, and they wait for it to finish
+    pub fn sweep(
+        &self,
+        network: &str,
+        options: SweepOptions,
+    ) -> Result<BTreeMap<IpAddr, Duration>, String> {
+        let hosts = sweep::hosts(network)?;
+        let _rounds = match self.rounds.try_lock() {
+            Ok(rounds) => rounds,
+            Err(_) => return Err("the pinger is already running".to_string()),
+        };
+        let total = hosts.len();
+        let mut pending: VecDeque<(IpAddr, u32)> =
+            hosts.into_iter().map(|addr| (addr, 0)).collect();
+        let mut alive = BTreeMap::new();
+        let mut done = 0;
+        while !pending.is_empty() {
+            let batch = Arc::new(Mutex::new(BTreeMap::new()));
+            let mut attempts = BTreeMap::new();
+            while attempts.len() < options.parallelism {
+                let (addr, attempt) = match pending.pop_front() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let (key, mut ping) = match self.new_target(addr, options.target.clone()) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        // give back the bindings the batch took so far
+                        for (_, binding, _) in batch.lock().unwrap().keys() {
+                            self.release(binding);
+                        }
+                        return Err(e);
+                    }
+                };
+                ping.state = None;
+                batch.lock().unwrap().insert(key, ping);
+                attempts.insert(addr, attempt);
+            }
+
+            // run one round over the batch with a results channel of its own.  Hosts
+            // are retried per SweepOptions::retries only, not the pinger's retry policy
+            let (results_sender, results) = channel();
+            send_pings(
+                self.size,
+                Arc::new(Mutex::new(true)),
+                ResultSink::Channel(results_sender),
+                self.thread_tx.clone(),
+                self.thread_rx.clone(),
+                self.senders.clone(),
+                batch.clone(),
+                self.max_rtt.clone(),
+                options.interval,
+                RetryPolicy::default(),
+                Arc::new(Mutex::new(0)),
+            );
+            for (_, binding, _) in batch.lock().unwrap().keys() {
+                self.release(binding);
+            }
+            for result in results.try_iter() {
+                match result {
+                    PingResult::Receive { addr, rtt, .. }
+                    | PingResult::Timestamp { addr, rtt, .. }
+                    | PingResult::Neighbor { addr, rtt, .. }
+                    | PingResult::NodeInfo { addr, rtt, .. } => {
+                        alive.insert(addr, rtt);
+                        done += 1;
+                    }
+                    PingResult::Idle { addr, .. } => match attempts.get(&addr) {
+                        Some(&attempt) if attempt < options.retries => {
+                            pending.push_back((addr, attempt + 1))
+                        }
+                        _ => done += 1,
+                    },
+                    _ => {}
+                }
+            }
+            if let Some(ref progress) = options.progress {
+                let _ = progress.send(SweepProgress {
+                    done,
+                    total,
+                    alive: alive.len(),
+                });
+            }
+        }
+        Ok(alive)
+    }
+
+    // merge per-target options with the pinger defaults for the address family
+    fn resolve_binding(&self, addr: IpAddr, options: TargetOptions) -> Binding {
+        let default = if addr.is_ipv4() {
+            &self.binding
+        } else {
+            &self.bindingv6
+        };
+        #[cfg(feature = "layer3")]
+        {
+            // layer 3 probes carry their own source and aren't sent through a bound
+            // socket, their replies arrive on the default one for the address family
+            if options.header.is_some() {
+                return default.clone();
+            }
+        }
+        let probe = options.probe;
+        let mut binding = Binding {
+            source: options.source.or(default.source),
+            interface: options.interface.or_else(|| default.interface.clone()),
+        };
+        if probe == Probe::Neighbor && binding.interface.is_none() {
+            // neighbor probes go out of the interface on the target's subnet
+            binding.interface = neighbor::find_interface(self.netns.as_deref(), addr).ok();
+        }
+        binding
+    }
 
-        // setup ipv4 listener
+    // resolve the binding for a new target and make sure a socket is open for it
+    fn target_binding(&self, addr: IpAddr, options: TargetOptions) -> Result<Binding, String> {
+        #[cfg(feature = "layer3")]
+        {
+            if let Some(ref header) = options.header {
+                header.validate(addr)?;
+                self.open_raw(addr.is_ipv6())?;
+            }
+        }
+        let probe = options.probe;
+        let binding = self.resolve_binding(addr, options);
+        if let Some(source) = binding.source {
+            if source.is_ipv4() != addr.is_ipv4() {
+                return Err(format!(
+                    "source address {} does not match the address family",
+                    source
+                ));
+            }
+        }
+        if probe == Probe::Neighbor {
+            if binding.interface.is_none() {
+                return Err(format!("no interface with a subnet containing {}", addr));
+            }
+            self.open_neighbor(&binding)?;
+        } else {
+            self.open_channel(addr.is_ipv6(), &binding)?;
+        }
+        *self
+            .senders
+            .lock()
+            .unwrap()
+            .users
+            .entry(binding.clone())
+            .or_insert(0) += 1;
+        Ok(binding)
+    }
+
+    // let go of a binding a target was using, closing its sockets and datalink
+    // channels once no target uses it.  The default binding's sockets stay open, the
+    // listeners read from them
+    fn release(&self, binding: &Binding) {
+        let mut senders = self.senders.lock().unwrap();
+        let users = match senders.users.get_mut(binding) {
+            Some(users) => {
+                *users -= 1;
+                *users
+            }
+            None => return,
+        };
+        if users > 0 || *binding == Binding::default() {
+            return;
+        }
+        debug!("Closing the sockets for {:?}", binding);
+        senders.users.remove(binding);
+        senders.tx.remove(binding);
+        senders.txv6.remove(binding);
+        senders.neighbor.remove(binding);
+    }
+
+    // allow the binding's ipv4 socket to send to broadcast addresses
+    fn enable_broadcast(&self, binding: &Binding) -> Result<(), String> {
+        match self.senders.lock().unwrap().tx.get(binding) {
+            Some(channel) => socket::set_broadcast(channel.tx.socket.fd).map_err(|e| e.to_string()),
+            None => Err(format!("no socket open for {:?}", binding)),
+        }
+    }
+
+    // open a datalink channel for neighbor probes on the binding's interface, unless
+    // one is already open, and start its listener
+    fn open_neighbor(&self, binding: &Binding) -> Result<(), String> {
+        let mut senders = self.senders.lock().unwrap();
+        if senders.neighbor.contains_key(binding) {
+            return Ok(());
+        }
+        let (channel, rx) = match neighbor::open_channel(self.netns.as_deref(), binding) {
+            Ok((channel, rx)) => (channel, rx),
+            Err(e) => return Err(e.to_string()),
+        };
+        self.start_neighbor_listener(binding.clone(), channel.mac(), channel.closed(), rx);
+        senders.neighbor.insert(binding.clone(), channel);
+        Ok(())
+    }
+
+    // open the header including socket for layer 3 targets, unless already open
+    #[cfg(feature = "layer3")]
+    fn open_raw(&self, ipv6: bool) -> Result<(), String> {
+        let mut senders = self.senders.lock().unwrap();
+        let raw = if ipv6 {
+            &mut senders.rawv6
+        } else {
+            &mut senders.raw
+        };
+        if raw.is_none() {
+            match layer3::open_raw(self.netns.as_deref(), ipv6) {
+                Ok(tx) => *raw = Some(tx),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(())
+    }
+
+    // open the unbound socket for the address family that every reply is read from,
+    // start its listener and send through it for targets with the default binding
+    fn open_listener(&self, ipv6: bool) -> Result<(), String> {
+        let (tx, rx) = match socket::open_channel(self.netns.as_deref(), ipv6, &Binding::default())
+        {
+            Ok((tx, rx)) => (tx, rx),
+            Err(e) => return Err(e.to_string()),
+        };
+        if ipv6 {
+            self.start_listenerv6(rx);
+        } else {
+            self.start_listener(rx);
+        }
+        let mut senders = self.senders.lock().unwrap();
+        let senders = if ipv6 {
+            &mut senders.txv6
+        } else {
+            &mut senders.tx
+        };
+        senders.insert(Binding::default(), Channel::new(tx, ipv6));
+        Ok(())
+    }
+
+    // open a send only socket for the binding, unless one is already open.  Replies
+    // to it are read by the listener
+    fn open_channel(&self, ipv6: bool, binding: &Binding) -> Result<(), String> {
+        let mut senders = self.senders.lock().unwrap();
+        let senders = if ipv6 {
+            &mut senders.txv6
+        } else {
+            &mut senders.tx
+        };
+        if senders.contains_key(binding) {
+            return Ok(());
+        }
+        let tx = match socket::open_channel(self.netns.as_deref(), ipv6, binding) {
+            Ok((tx, _)) => tx,
+            Err(e) => return Err(e.to_string()),
+        };
+        socket::discard_input(tx.socket.fd).map_err(|e| e.to_string())?;
+        senders.insert(binding.clone(), Channel::new(tx, ipv6));
+        Ok(())
+    }
+
+    fn start_listener(&self, mut receiver: TransportReceiver) {
+        // start icmp listener in the background and use internal channels for results
         let thread_tx = self.thread_tx.clone();
-        let rx = self.rx.clone();
-        let timer = self.timer.clone();
         let stop = self.stop.clone();
+        let capture = self.capture.clone();
 
-        thread::spawn(move || {
-            let mut receiver = rx.lock().unwrap();
-            let mut iter = icmp_packet_iter(&mut receiver);
-            loop {
-                match iter.next() {
-                    Ok((packet, addr)) => match IcmpEchoReplyPacket::new(packet.packet()) {
-                        Some(echo_reply) => {
-                            if packet.get_icmp_type() == icmp::IcmpTypes::EchoReply {
-                                let start_time = timer.read().unwrap();
-                                match thread_tx.send(ReceivedPing {
-                                    addr,
-                                    identifier: echo_reply.get_identifier(),
-                                    sequence_number: echo_reply.get_sequence_number(),
-                                    rtt: Instant::now().duration_since(*start_time),
-                                }) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        if !*stop.lock().unwrap() {
-                                            error!("Error sending ping result on channel: {}", e)
-                                        } else {
-                                            return;
-                                        }
-                                    }
-                                }
+        thread::spawn(move || loop {
+            match socket::recv(&mut receiver, false) {
+                Ok(received) => {
+                    // read the clocks before anything else, the capture included
+                    let (arrived, received_at) = (Instant::now(), SystemTime::now());
+                    if let Some(ref capture) = capture {
+                        capture.lock().unwrap().received(
+                            received.addr,
+                            received.destination,
+                            received.ttl,
+                            received.packet,
+                            received_at,
+                        );
+                    }
+                    // echo and timestamp replies carry the probe's identifier and
+                    // sequence number, port unreachables quote the udp probe instead
+                    let matched =
+                        if let Some(quoted) = udp::parse_unreachable(received.packet, false) {
+                            Some((quoted.addr, quoted.identifier, quoted.sequence_number, None))
+                        } else if let (Some(packet), Some(echo_reply)) = (
+                            IcmpPacket::new(received.packet),
+                            IcmpEchoReplyPacket::new(received.packet),
+                        ) {
+                            let timestamps = timestamp::parse_reply(received.packet, received_at);
+                            if packet.get_icmp_type() == icmp::IcmpTypes::EchoReply
+                                || timestamps.is_some()
+                            {
+                                Some((
+                                    received.addr,
+                                    echo_reply.get_identifier(),
+                                    echo_reply.get_sequence_number(),
+                                    timestamps,
+                                ))
                             } else {
                                 debug!(
-                                    "ICMP type other than reply (0) received from {:?}: {:?}",
-                                    addr,
+                                    "ICMP type other than reply (0 or 14) received from {:?}: {:?}",
+                                    received.addr,
                                     packet.get_icmp_type()
                                 );
+                                None
+                            }
+                        } else {
+                            None
+                        };
+                    if let Some((addr, identifier, sequence_number, timestamps)) = matched {
+                        match thread_tx.send(ReceivedPing {
+                            addr,
+                            binding: None,
+                            identifier,
+                            sequence_number,
+                            arrived,
+                            ttl: received.ttl,
+                            size: received.packet.len(),
+                            route: route::parse(received.ip_options),
+                            timestamps,
+                            mac: None,
+                            names: None,
+                            received_at,
+                        }) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                if !*stop.lock().unwrap() {
+                                    error!("Error sending ping result on channel: {}", e)
+                                } else {
+                                    return;
+                                }
                             }
                         }
-                        None => {}
-                    },
-                    Err(e) => {
-                        error!("An error occurred while reading: {}", e);
                     }
                 }
+                Err(e) => {
+                    events::read_failed(&e);
+                }
             }
         });
+    }
 
-        // setup ipv6 listener
+    fn start_listenerv6(&self, mut receiver: TransportReceiver) {
+        // start icmpv6 listener in the background and use internal channels for results
         let thread_txv6 = self.thread_tx.clone();
-        let rxv6 = self.rxv6.clone();
-        let timerv6 = self.timer.clone();
         let stopv6 = self.stop.clone();
+        let capturev6 = self.capture.clone();
+
+        thread::spawn(move || loop {
+            match socket::recv(&mut receiver, true) {
+                Ok(received) => {
+                    // read the clocks before anything else, the capture included
+                    let (arrived, received_at) = (Instant::now(), SystemTime::now());
+                    if let Some(ref capture) = capturev6 {
+                        capture.lock().unwrap().received(
+                            received.addr,
+                            received.destination,
+                            received.ttl,
+                            received.packet,
+                            received_at,
+                        );
+                    }
+                    let matched = if let Some(quoted) =
+                        udp::parse_unreachable(received.packet, true)
+                    {
+                        Some((quoted.addr, quoted.identifier, quoted.sequence_number, None))
+                    } else if let Some((identifier, sequence_number, names)) =
+                        nodeinfo::parse_reply(received.packet)
+                    {
+                        Some((received.addr, identifier, sequence_number, Some(names)))
+                    } else if let (Some(packet), Some(echo_reply)) = (
+                        Icmpv6Packet::new(received.packet),
+                        Icmpv6EchoReplyPacket::new(received.packet),
+                    ) {
+                        if packet.get_icmpv6_type() == icmpv6::Icmpv6Types::EchoReply {
+                            Some((
+                                received.addr,
+                                echo_reply.get_identifier(),
+                                echo_reply.get_sequence_number(),
+                                None,
+                            ))
+                        } else {
+                            debug!(
+                                    "ICMPv6 type other than reply (129 or 140) received from {:?}: {:?}",
+                                    received.addr,
+                                    packet.get_icmpv6_type()
+                                );
+                            None
+                        }
+                    } else {
+                        None
+                    };
+                    if let Some((addr, identifier, sequence_number, names)) = matched {
+                        match thread_txv6.send(ReceivedPing {
+                            addr,
+                            binding: None,
+                            identifier,
+                            sequence_number,
+                            arrived,
+                            ttl: received.ttl,
+                            size: received.packet.len(),
+                            route: None,
+                            timestamps: None,
+                            mac: None,
+                            names,
+                            received_at,
+                        }) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                if !*stopv6.lock().unwrap() {
+                                    error!("Error sending ping result on channel: {}", e)
+                                } else {
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    events::read_failed(&e);
+                }
+            }
+        });
+    }
+
+    fn start_neighbor_listener(
+        &self,
+        binding: Binding,
+        mac: MacAddr,
+        closed: Arc<Mutex<bool>>,
+        mut receiver: Box<dyn DataLinkReceiver>,
+    ) {
+        // start the ARP and NDP listener in the background and use internal channels for results
+        let thread_tx = self.thread_tx.clone();
+        let stop = self.stop.clone();
 
         thread::spawn(move || {
-            let mut receiver = rxv6.lock().unwrap();
-            let mut iter = icmpv6_packet_iter(&mut receiver);
-            loop {
-                match iter.next() {
-                    Ok((packet, addr)) => match Icmpv6EchoReplyPacket::new(packet.packet()) {
-                        Some(echo_reply) => {
-                            if packet.get_icmpv6_type() == icmpv6::Icmpv6Types::EchoReply {
-                                let start_time = timerv6.read().unwrap();
-                                match thread_txv6.send(ReceivedPing {
-                                    addr,
-                                    identifier: echo_reply.get_identifier(),
-                                    sequence_number: echo_reply.get_sequence_number(),
-                                    rtt: Instant::now().duration_since(*start_time),
-                                }) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        if !*stopv6.lock().unwrap() {
-                                            error!("Error sending ping result on channel: {}", e)
-                                        } else {
-                                            return;
-                                        }
+            // back off while reads keep failing, and give up if they don't recover.
+            // The receiver closes when the thread returns, once the binding is released
+            let mut failures = 0;
+            while !*closed.lock().unwrap() {
+                match receiver.next() {
+                    Ok(frame) => {
+                        failures = 0;
+                        if let Some((addr, peer)) = neighbor::parse_reply(frame, mac) {
+                            match thread_tx.send(ReceivedPing {
+                                addr,
+                                binding: Some(binding.clone()),
+                                identifier: 0,
+                                sequence_number: 0,
+                                arrived: Instant::now(),
+                                ttl: None,
+                                size: frame.len(),
+                                route: None,
+                                timestamps: None,
+                                mac: Some(peer),
+                                names: None,
+                                received_at: SystemTime::now(),
+                            }) {
+                                Ok(_) => {}
+                                Err(e) => {
+                                    if !*stop.lock().unwrap() {
+                                        error!("Error sending ping result on channel: {}", e)
+                                    } else {
+                                        return;
                                     }
                                 }
-                            } else {
-                                debug!(
-                                    "ICMPv6 type other than reply (129) received from {:?}: {:?}",
-                                    addr,
-                                    packet.get_icmpv6_type()
-                                );
                             }
                         }
-                        None => {}
-                    },
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
                     Err(e) => {
-                        error!("An error occurred while reading: {}", e);
+                        if *stop.lock().unwrap() {
+                            return;
+                        }
+                        events::read_failed(&e);
+                        failures += 1;
+                        if failures >= neighbor::MAX_READ_FAILURES {
+                            error!("Giving up on the neighbor listener for {:?}", binding);
+                            return;
+                        }
+                        thread::sleep(neighbor::READ_BACKOFF * failures);
                     }
                 }
             }
@@ -314,6 +1219,14 @@ impl Pinger {
 }
 
 #[cfg(test)]
+#[allow(
+    clippy::assertions_on_constants,
+    clippy::bool_assert_comparison,
+    clippy::collapsible_match,
+    clippy::single_match,
+    clippy::unnecessary_cast,
+    clippy::useless_vec
+)]
 mod tests {
     use super::*;
 
@@ -329,10 +1242,14 @@ mod tests {
 
                 match test_pinger.results_sender.send(PingResult::Idle {
                     addr: "127.0.0.1".parse::<IpAddr>().unwrap(),
+                    binding: Binding::default(),
+                    attempts: 1,
+                    round_id: 1,
+                    probe: Probe::Echo,
                 }) {
                     Ok(_) => match test_channel.recv() {
                         Ok(result) => match result {
-                            PingResult::Idle { addr } => {
+                            PingResult::Idle { addr, .. } => {
                                 assert_eq!(addr, "127.0.0.1".parse::<IpAddr>().unwrap());
                             }
                             _ => {}
@@ -355,20 +1272,20 @@ mod tests {
             Ok((test_pinger, _)) => {
                 test_pinger.add_ipaddr("127.0.0.1");
                 assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
-                assert!(test_pinger
-                    .targets
-                    .lock()
-                    .unwrap()
-                    .contains_key(&"127.0.0.1".parse::<IpAddr>().unwrap()));
+                assert!(test_pinger.targets.lock().unwrap().contains_key(&(
+                    "127.0.0.1".parse::<IpAddr>().unwrap(),
+                    Binding::default(),
+                    false
+                )));
 
                 test_pinger.remove_ipaddr("127.0.0.1");
                 assert_eq!(test_pinger.targets.lock().unwrap().len(), 0);
                 assert_eq!(
-                    test_pinger
-                        .targets
-                        .lock()
-                        .unwrap()
-                        .contains_key(&"127.0.0.1".parse::<IpAddr>().unwrap()),
+                    test_pinger.targets.lock().unwrap().contains_key(&(
+                        "127.0.0.1".parse::<IpAddr>().unwrap(),
+                        Binding::default(),
+                        false
+                    )),
                     false
                 );
             }
@@ -407,10 +1324,10 @@ mod tests {
                 for _ in test_addrs.iter() {
                     match test_channel.recv() {
                         Ok(result) => match result {
-                            PingResult::Idle { addr } => {
+                            PingResult::Idle { addr, .. } => {
                                 assert_eq!("7.7.7.7".parse::<IpAddr>().unwrap(), addr);
                             }
-                            PingResult::Receive { addr, rtt: _ } => {
+                            PingResult::Receive { addr, .. } => {
                                 if addr == "::1".parse::<IpAddr>().unwrap()
                                     || addr == "127.0.0.1".parse::<IpAddr>().unwrap()
                                 {
@@ -433,4 +1350,735 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_builder() {
+        match PingerBuilder::new()
+            .max_rtt(500)
+            .size(32)
+            .source("127.0.0.1".parse::<IpAddr>().unwrap())
+            .build()
+        {
+            Ok((test_pinger, _)) => {
+                assert_eq!(test_pinger.max_rtt, Arc::new(Duration::from_millis(500)));
+                assert_eq!(test_pinger.size, 32);
+                assert_eq!(
+                    test_pinger.binding.source,
+                    Some("127.0.0.1".parse::<IpAddr>().unwrap())
+                );
+                assert_eq!(test_pinger.bindingv6.source, None);
+            }
+            Err(e) => {
+                panic!("Test failed: {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_bindings() {
+        // ping the same address via the default socket and via a socket bound
+        // to the loopback interface, and check each result reports its binding
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                let bound = Binding {
+                    source: Some("127.0.0.1".parse::<IpAddr>().unwrap()),
+                    interface: Some("lo".to_string()),
+                };
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr_with(
+                    "127.0.0.1",
+                    TargetOptions::new()
+                        .source("127.0.0.1".parse::<IpAddr>().unwrap())
+                        .interface("lo"),
+                );
+                // mismatched address family and unknown interfaces are rejected
+                test_pinger.add_ipaddr_with(
+                    "::1",
+                    TargetOptions::new().source("127.0.0.1".parse::<IpAddr>().unwrap()),
+                );
+                test_pinger
+                    .add_ipaddr_with("127.0.0.1", TargetOptions::new().interface("fpnoexist0"));
+                // as is the same address and binding with other probe options
+                test_pinger.add_ipaddr_with("127.0.0.1", TargetOptions::new().ttl(5));
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 2);
+
+                test_pinger.ping_once();
+                let mut bindings = Vec::new();
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive { binding, .. }) => bindings.push(binding),
+                        _ => panic!("expected a reply from 127.0.0.1"),
+                    }
+                }
+                bindings.sort();
+                assert_eq!(bindings, vec![Binding::default(), bound.clone()]);
+
+                test_pinger.remove_ipaddr_with(
+                    "127.0.0.1",
+                    TargetOptions::new()
+                        .source("127.0.0.1".parse::<IpAddr>().unwrap())
+                        .interface("lo"),
+                );
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
+                // the binding's socket closes with its last target
+                assert!(!test_pinger.senders.lock().unwrap().tx.contains_key(&bound));
+            }
+            Err(e) => {
+                panic!("Test failed: {}", e)
+            }
+        }
+    }
+
+    #[test]
+    fn test_netns() {
+        match PingerBuilder::new()
+            .max_rtt(500)
+            .netns("/proc/self/ns/net")
+            .build()
+        {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.ping_once();
+                match test_channel.recv() {
+                    Ok(PingResult::Receive { addr, .. }) => {
+                        assert_eq!(addr, "127.0.0.1".parse::<IpAddr>().unwrap())
+                    }
+                    _ => panic!("expected a reply from 127.0.0.1"),
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+
+        assert!(PingerBuilder::new()
+            .netns("/var/run/netns/fastping-nonexistent")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_ttl_tos() {
+        match PingerBuilder::new().max_rtt(500).ttl(32).build() {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr_with("::1", TargetOptions::new().ttl(1).tos(46 << 2));
+                {
+                    let targets = test_pinger.targets.lock().unwrap();
+                    let v4 = &targets[&(
+                        "127.0.0.1".parse::<IpAddr>().unwrap(),
+                        Binding::default(),
+                        false,
+                    )];
+                    assert_eq!((v4.ttl, v4.tos), (Some(32), None));
+                    let v6 =
+                        &targets[&("::1".parse::<IpAddr>().unwrap(), Binding::default(), false)];
+                    assert_eq!((v6.ttl, v6.tos), (Some(1), Some(46 << 2)));
+                }
+
+                // a single hop is enough to reach loopback
+                test_pinger.ping_once();
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive { .. }) => {}
+                        _ => panic!("expected replies from loopback"),
+                    }
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_reply_ttl() {
+        match Pinger::new(Some(500), Some(24)) {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr("::1");
+                let before = SystemTime::now();
+                test_pinger.ping_once();
+                let after = SystemTime::now();
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive {
+                            rtt,
+                            ttl,
+                            size,
+                            hops,
+                            reply,
+                            ..
+                        }) => {
+                            assert!(ttl.is_some());
+                            assert_eq!(size, 24);
+                            // loopback replies don't cross a router
+                            assert_eq!(hops, Some(0));
+                            assert_eq!(reply.sequence_number, 1);
+                            assert_eq!((reply.size, reply.ttl), (size, ttl));
+                            assert_eq!(reply.probe, Probe::Echo);
+                            // the probe went out and came back during ping_once
+                            assert!(before <= reply.sent_at && reply.sent_at <= reply.received_at);
+                            assert!(reply.received_at <= after);
+                            assert!(rtt <= after.duration_since(before).unwrap());
+                        }
+                        _ => panic!("expected replies from loopback"),
+                    }
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[cfg(feature = "layer3")]
+    #[test]
+    fn test_layer3() {
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr_with(
+                    "127.0.0.1",
+                    TargetOptions::new().ttl(9).ip_header(
+                        IpHeader::new()
+                            .source("127.0.0.1".parse::<IpAddr>().unwrap())
+                            .identification(0xbeef)
+                            .dont_fragment(true),
+                    ),
+                );
+                test_pinger.add_ipaddr_with(
+                    "::1",
+                    TargetOptions::new().ip_header(
+                        IpHeader::new()
+                            .source("::1".parse::<IpAddr>().unwrap())
+                            .flow_label(7),
+                    ),
+                );
+                // ipv6 headers need a source
+                test_pinger.add_ipaddr_with("::2", TargetOptions::new().ip_header(IpHeader::new()));
+                // a regular target on the same address is kept apart
+                test_pinger.add_ipaddr("127.0.0.1");
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 3);
+
+                test_pinger.ping_once();
+                for _ in 0..3 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive { binding, .. }) => {
+                            assert_eq!(binding, Binding::default())
+                        }
+                        _ => panic!("expected replies from loopback"),
+                    }
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_record_route() {
+        match PingerBuilder::new()
+            .max_rtt(500)
+            .record(RecordMode::Route)
+            .build()
+        {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr_with("::1", TargetOptions::new().record(RecordMode::Route));
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
+
+                test_pinger.ping_once();
+                match test_channel.recv() {
+                    Ok(PingResult::Receive { route, .. }) => {
+                        let route = route.expect("reply should carry the recorded route");
+                        assert!(route
+                            .addresses
+                            .contains(&"127.0.0.1".parse::<std::net::Ipv4Addr>().unwrap()));
+                    }
+                    _ => panic!("expected a reply from 127.0.0.1"),
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_timestamp_probe() {
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger
+                    .add_ipaddr_with("127.0.0.1", TargetOptions::new().probe(Probe::Timestamp));
+                test_pinger.add_ipaddr_with("::1", TargetOptions::new().probe(Probe::Timestamp));
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
+
+                test_pinger.ping_once();
+                match test_channel.recv() {
+                    Ok(PingResult::Timestamp {
+                        addr, timestamps, ..
+                    }) => {
+                        assert_eq!(addr, "127.0.0.1".parse::<IpAddr>().unwrap());
+                        assert!(timestamps.is_standard());
+                        // same clock on both ends
+                        assert!(timestamps.clock_offset().abs() < 100);
+                    }
+                    _ => panic!("expected a timestamp reply from 127.0.0.1"),
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_tcp_probe() {
+        // icmp and tcp targets side by side: an open port on 127.0.0.1, the same
+        // port on 127.0.0.2 where nothing listens and so answers with a RST, and ::1
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger
+                    .add_ipaddr_with("127.0.0.1", TargetOptions::new().probe(Probe::Tcp(port)));
+                test_pinger
+                    .add_ipaddr_with("127.0.0.2", TargetOptions::new().probe(Probe::Tcp(port)));
+                test_pinger.add_ipaddr("::1");
+                test_pinger.ping_once();
+                for _ in 0..3 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive {
+                            addr, ttl, size, ..
+                        }) => {
+                            if addr.is_ipv4() {
+                                assert_eq!(ttl, None);
+                                assert_eq!(size, 0);
+                            } else {
+                                assert!(ttl.is_some());
+                            }
+                        }
+                        _ => panic!("expected every target to answer"),
+                    }
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_udp_probe() {
+        // a port nothing listens on, answered with a port unreachable
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                for target in ["127.0.0.1", "::1"].iter() {
+                    test_pinger
+                        .add_ipaddr_with(target, TargetOptions::new().probe(Probe::Udp(port)));
+                }
+                test_pinger.ping_once();
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive { ttl, .. }) => assert!(ttl.is_some()),
+                        _ => panic!("expected a port unreachable from every target"),
+                    }
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    // run an ip(8) command for test network setup
+    fn ip(args: &str) -> String {
+        let output = std::process::Command::new("ip")
+            .args(args.split(' '))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "ip {} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // a veth pair fp<name>0 - fp<name>1 with its far end in the network namespace
+    // fptest<name>, addressed from <v4>.0/24 and <v6>:/64.  Deleting the namespace
+    // when dropped takes the pair with it, even if the test panics
+    struct VethNetns {
+        netns: String,
+    }
+
+    impl VethNetns {
+        fn new(name: &str, v4: &str, v6: &str) -> VethNetns {
+            let netns = format!("fptest{}", name);
+            let _ = std::process::Command::new("ip")
+                .args(["netns", "del", &netns])
+                .status();
+            ip(&format!("netns add {}", netns));
+            let veth = VethNetns { netns };
+            ip(&format!(
+                "link add fp{0}0 type veth peer name fp{0}1 netns {1}",
+                name, veth.netns
+            ));
+            ip(&format!("addr add {}.1/24 dev fp{}0", v4, name));
+            ip(&format!("addr add {}:1/64 dev fp{}0 nodad", v6, name));
+            ip(&format!("link set fp{}0 up", name));
+            ip(&format!(
+                "-n {} addr add {}.2/24 dev fp{}1",
+                veth.netns, v4, name
+            ));
+            ip(&format!(
+                "-n {} addr add {}:2/64 dev fp{}1 nodad",
+                veth.netns, v6, name
+            ));
+            ip(&format!("-n {} link set fp{}1 up", veth.netns, name));
+            veth
+        }
+    }
+
+    impl Drop for VethNetns {
+        fn drop(&mut self) {
+            let _ = std::process::Command::new("ip")
+                .args(["netns", "del", &self.netns])
+                .status();
+        }
+    }
+
+    #[test]
+    fn test_neighbor_probe() {
+        let _veth = VethNetns::new("nbr", "10.203.5", "fd00:35:");
+        let peer = ip("-n fptestnbr -br link show dev fpnbr1");
+        let peer = peer
+            .split_whitespace()
+            .nth(2)
+            .unwrap()
+            .parse::<MacAddr>()
+            .unwrap();
+
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                for target in ["10.203.5.2", "fd00:35::2"].iter() {
+                    test_pinger
+                        .add_ipaddr_with(target, TargetOptions::new().probe(Probe::Neighbor));
+                }
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 2);
+                test_pinger.ping_once();
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Neighbor { binding, mac, .. }) => {
+                            assert_eq!(binding.interface, Some("fpnbr0".to_string()));
+                            assert_eq!(mac, peer);
+                        }
+                        _ => panic!("expected a neighbor reply from every target"),
+                    }
+                }
+
+                // releasing the binding closes the listener's packet socket too
+                let ifindex = std::fs::read_to_string("/sys/class/net/fpnbr0/ifindex").unwrap();
+                let bound = || {
+                    std::fs::read_to_string("/proc/net/packet")
+                        .unwrap()
+                        .lines()
+                        .skip(1)
+                        .filter(|line| line.split_whitespace().nth(4) == Some(ifindex.trim()))
+                        .count()
+                };
+                assert_eq!(bound(), 1);
+                for target in ["10.203.5.2", "fd00:35::2"].iter() {
+                    test_pinger.remove_ipaddr(target);
+                }
+                thread::sleep(neighbor::READ_TIMEOUT * 3);
+                assert_eq!(bound(), 0);
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_discover() {
+        let _veth = VethNetns::new("dsc", "10.203.36", "fd00:36:");
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr_with(
+                    "ff02::1",
+                    TargetOptions::new().interface("fpdsc0").discover(),
+                );
+                // not multicast
+                test_pinger.add_ipaddr_with("fd00:36::2", TargetOptions::new().discover());
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 1);
+
+                // the far end's link-local address may still be tentative at first
+                let peer = ip("-n fptestdsc -6 -o addr show dev fpdsc1");
+                let mut found = false;
+                for _ in 0..5 {
+                    test_pinger.ping_once();
+                    while let Ok(result) = test_channel.try_recv() {
+                        if let PingResult::Discovered { addr, binding, .. } = result {
+                            assert_eq!(binding.interface, Some("fpdsc0".to_string()));
+                            found |= peer.contains(&format!(" {}/", addr));
+                        }
+                    }
+                    if found {
+                        break;
+                    }
+                }
+                assert!(found, "expected a reply from the far end of the veth pair");
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_sweep_release() {
+        // only the lower half of the /23 is on the veth pair, so the sweep fails
+        // once it gets to the upper half with the first batch still open
+        let _veth = VethNetns::new("swp", "10.203.38", "fd00:38:");
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, _)) => {
+                assert!(test_pinger
+                    .sweep(
+                        "10.203.38.0/23",
+                        SweepOptions::new()
+                            .parallelism(512)
+                            .target(TargetOptions::new().probe(Probe::Neighbor)),
+                    )
+                    .is_err());
+                let senders = test_pinger.senders.lock().unwrap();
+                assert!(senders.neighbor.is_empty());
+                assert!(!senders
+                    .users
+                    .keys()
+                    .any(|binding| binding.interface.is_some()));
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        // all of 127.0.0.0/29 answers on loopback
+        let (progress_sender, progress) = channel();
+        match Pinger::new(Some(500), None) {
+            Ok((test_pinger, _)) => {
+                let alive = test_pinger
+                    .sweep(
+                        "127.0.0.0/29",
+                        SweepOptions::new()
+                            .parallelism(4)
+                            .rate(1000)
+                            .progress(progress_sender),
+                    )
+                    .unwrap();
+                assert_eq!(alive.len(), 6);
+                assert!(alive.contains_key(&"127.0.0.6".parse::<IpAddr>().unwrap()));
+
+                let updates: Vec<SweepProgress> = progress.try_iter().collect();
+                assert_eq!(updates.len(), 2);
+                assert_eq!(
+                    updates[1],
+                    SweepProgress {
+                        done: 6,
+                        total: 6,
+                        alive: 6
+                    }
+                );
+
+                assert!(test_pinger.sweep("::/64", SweepOptions::new()).is_err());
+
+                // not while the pinger runs rounds of its own
+                let rounds = test_pinger.rounds.lock().unwrap();
+                assert!(test_pinger
+                    .sweep("127.0.0.0/30", SweepOptions::new())
+                    .is_err());
+                drop(rounds);
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_retries() {
+        match PingerBuilder::new()
+            .max_rtt(1000)
+            .retries(2)
+            .retry_timeout(100)
+            .backoff(2.0)
+            .build()
+        {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr("7.7.7.7");
+                let start = Instant::now();
+                test_pinger.ping_once();
+                // waits of 100, 200 and 400ms
+                assert!(start.elapsed() >= Duration::from_millis(700));
+                assert!(start.elapsed() < Duration::from_millis(1000));
+                for _ in 0..2 {
+                    match test_channel.recv() {
+                        Ok(PingResult::Receive { attempts, .. }) => assert_eq!(attempts, 1),
+                        Ok(PingResult::Idle { addr, attempts, .. }) => {
+                            assert_eq!(addr, "7.7.7.7".parse::<IpAddr>().unwrap());
+                            assert_eq!(attempts, 3);
+                        }
+                        _ => panic!("unexpected result"),
+                    }
+                }
+                match test_channel.recv() {
+                    Ok(PingResult::RoundComplete {
+                        round_id,
+                        duration,
+                        sent,
+                        received,
+                        idle,
+                        ..
+                    }) => {
+                        assert_eq!(round_id, 1);
+                        assert!(duration >= Duration::from_millis(700));
+                        assert_eq!((sent, received, idle), (4, 1, 1));
+                    }
+                    _ => panic!("expected the round to complete"),
+                }
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_state_changes() {
+        match PingerBuilder::new()
+            .max_rtt(200)
+            .states(StateThresholds::new().up_after(1).down_after(1))
+            .build()
+        {
+            Ok((test_pinger, test_channel)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr("7.7.7.7");
+                test_pinger.ping_once();
+                let mut changes = Vec::new();
+                for _ in 0..4 {
+                    match test_channel.recv() {
+                        Ok(PingResult::StateChanged { addr, from, to, .. }) => {
+                            changes.push((addr, from, to))
+                        }
+                        Ok(PingResult::Receive { .. }) | Ok(PingResult::Idle { .. }) => {}
+                        _ => panic!("unexpected result"),
+                    }
+                }
+                changes.sort();
+                assert_eq!(
+                    changes,
+                    vec![
+                        (
+                            "7.7.7.7".parse::<IpAddr>().unwrap(),
+                            TargetState::Unknown,
+                            TargetState::Down
+                        ),
+                        (
+                            "127.0.0.1".parse::<IpAddr>().unwrap(),
+                            TargetState::Unknown,
+                            TargetState::Up
+                        ),
+                    ]
+                );
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_handler() {
+        struct Counter(Sender<(&'static str, IpAddr)>);
+
+        impl ResultHandler for Counter {
+            fn on_receive(&mut self, result: PingResult) {
+                if let PingResult::Receive { addr, .. } = result {
+                    self.0.send(("receive", addr)).unwrap();
+                }
+            }
+
+            fn on_idle(&mut self, result: PingResult) {
+                if let PingResult::Idle { addr, .. } = result {
+                    self.0.send(("idle", addr)).unwrap();
+                }
+            }
+
+            fn on_round_complete(&mut self, _result: PingResult) {
+                self.0.send(("round", IpAddr::from([0, 0, 0, 0]))).unwrap();
+            }
+        }
+
+        let (sender, calls) = channel();
+        match PingerBuilder::new()
+            .max_rtt(200)
+            .build_with_handler(Counter(sender))
+        {
+            Ok(test_pinger) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.add_ipaddr("7.7.7.7");
+                test_pinger.ping_once();
+                let calls: Vec<_> = calls.try_iter().collect();
+                assert_eq!(calls.len(), 3);
+                assert!(calls.contains(&("receive", "127.0.0.1".parse::<IpAddr>().unwrap())));
+                assert!(calls.contains(&("idle", "7.7.7.7".parse::<IpAddr>().unwrap())));
+                assert_eq!(calls[2].0, "round");
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_block_unlocked() {
+        match PingerBuilder::new()
+            .max_rtt(200)
+            .build_bounded(1, OverflowPolicy::Block)
+        {
+            Ok((test_pinger, results)) => {
+                let test_pinger = Arc::new(test_pinger);
+                test_pinger.add_ipaddr("7.7.7.7");
+                test_pinger.add_ipaddr("7.7.7.8");
+                let pinger = test_pinger.clone();
+                let round = thread::spawn(move || pinger.ping_once());
+                // the second idle result is waiting for room, the targets aren't locked
+                thread::sleep(Duration::from_millis(500));
+                test_pinger.add_ipaddr("127.0.0.1");
+                let mut idle = 0;
+                loop {
+                    match results.recv() {
+                        Ok(PingResult::Idle { .. }) => idle += 1,
+                        Ok(PingResult::RoundComplete { .. }) => break,
+                        _ => {}
+                    }
+                }
+                assert_eq!(idle, 2);
+                round.join().unwrap();
+                assert_eq!(test_pinger.targets.lock().unwrap().len(), 3);
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let path = std::env::temp_dir().join(format!("fastping-{}.pcap", std::process::id()));
+        match PingerBuilder::new()
+            .max_rtt(200)
+            .capture(CaptureOptions::new(&path))
+            .build()
+        {
+            Ok((test_pinger, results)) => {
+                test_pinger.add_ipaddr("127.0.0.1");
+                test_pinger.ping_once();
+                assert!(matches!(results.recv(), Ok(PingResult::Receive { .. })));
+                // the request, as sent and as looped back, and the reply
+                let data = std::fs::read(&path).unwrap();
+                let mut packets = Vec::new();
+                let mut i = 24;
+                while i + 16 <= data.len() {
+                    let length =
+                        u32::from_le_bytes([data[i + 8], data[i + 9], data[i + 10], data[i + 11]])
+                            as usize;
+                    // icmp type after the 20 byte ipv4 header, and the destination
+                    let packet = &data[i + 16..i + 16 + length];
+                    packets.push((packet[20], &packet[16..20]));
+                    i += 16 + length;
+                }
+                assert_eq!(i, data.len());
+                assert!(packets.iter().any(|&(icmp_type, _)| icmp_type == 8));
+                // the reply is captured with the destination it arrived for
+                assert!(packets.contains(&(0, &[127, 0, 0, 1][..])));
+                std::fs::remove_file(&path).unwrap();
+            }
+            Err(e) => panic!("Test failed: {}", e),
+        }
+    }
 }
@@ -0,0 +1,376 @@
+// Neighbor probes for targets on a directly connected subnet, which answer ARP
+// (ipv4) or Neighbor Solicitations (ipv6) even when they drop icmp.  Requests go
+// out of a datalink channel on the target's interface and the reply reports the
+// target's MAC address.  Neither protocol has an identifier or sequence number, so
+// any reply to us from a target counts for its current probe, as long as it came
+// in after the probe went out.  Gratuitous ARP and unsolicited advertisements don't
+use ping::Ping;
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use pnet::packet::arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmpv6::{self, Icmpv6Packet, MutableIcmpv6Packet};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv6::{Ipv6Packet, MutableIpv6Packet};
+use pnet::packet::Packet;
+use pnet::util::MacAddr;
+use socket::{self, Binding};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// icmpv6 neighbor solicitation and advertisement types (RFC 4861)
+const NEIGHBOR_SOLICIT: u8 = 135;
+const NEIGHBOR_ADVERT: u8 = 136;
+// solicited flag of a neighbor advertisement
+const SOLICITED: u8 = 0x40;
+
+// reads in a row a listener lets fail, backing off READ_BACKOFF longer each time,
+// before it gives up on the channel
+pub const MAX_READ_FAILURES: u32 = 10;
+pub const READ_BACKOFF: Duration = Duration::from_millis(100);
+// how long a listener waits for a frame before checking whether its channel was
+// closed
+pub const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// sender end of a datalink channel along with the interface details requests
+// are sent with.  Dropping it tells the listener to close the receiver end
+pub struct NeighborChannel {
+    tx: Box<dyn DataLinkSender>,
+    interface: NetworkInterface,
+    closed: Arc<Mutex<bool>>,
+}
+
+impl NeighborChannel {
+    // MAC address of the channel's interface, which replies are sent to
+    pub fn mac(&self) -> MacAddr {
+        self.interface.mac.unwrap_or_default()
+    }
+
+    // flag set once the channel is dropped, for the listener to check between reads
+    pub fn closed(&self) -> Arc<Mutex<bool>> {
+        self.closed.clone()
+    }
+}
+
+impl Drop for NeighborChannel {
+    fn drop(&mut self) {
+        *self.closed.lock().unwrap() = true;
+    }
+}
+
+// name of the interface with a subnet containing addr, for targets added
+// without an interface
+pub fn find_interface(netns: Option<&Path>, addr: IpAddr) -> Result<String, io::Error> {
+    socket::in_netns(netns, move || {
+        datalink::interfaces()
+            .into_iter()
+            .find(|iface| !iface.is_loopback() && iface.ips.iter().any(|ip| ip.contains(addr)))
+            .map(|iface| iface.name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no interface with a subnet containing {}", addr),
+                )
+            })
+    })
+}
+
+// open a datalink channel on the binding's interface
+pub fn open_channel(
+    netns: Option<&Path>,
+    binding: &Binding,
+) -> Result<(NeighborChannel, Box<dyn DataLinkReceiver>), io::Error> {
+    let name = match binding.interface {
+        Some(ref name) => name.clone(),
+        None => return Err(io::Error::other("neighbor probes need an interface")),
+    };
+    socket::in_netns(netns, move || {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == name)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("no interface {}", name))
+            })?;
+        if interface.mac.is_none() {
+            return Err(io::Error::other(format!("{} has no MAC address", name)));
+        }
+        let config = datalink::Config {
+            read_timeout: Some(READ_TIMEOUT),
+            ..Default::default()
+        };
+        match datalink::channel(&interface, config)? {
+            Channel::Ethernet(tx, rx) => Ok((
+                NeighborChannel {
+                    tx,
+                    interface,
+                    closed: Arc::new(Mutex::new(false)),
+                },
+                rx,
+            )),
+            _ => Err(io::Error::other(format!(
+                "{} is not an ethernet interface",
+                name
+            ))),
+        }
+    })
+}
+
+// send the next ARP request or Neighbor Solicitation to a target, from the given
+// source address or else one of the interface's own
+pub fn send_probe(
+    channel: &mut NeighborChannel,
+    ping: &mut Ping,
+    source: Option<IpAddr>,
+) -> Result<usize, io::Error> {
+    ping.increment_sequence_number();
+    let mac = channel.mac();
+    let source = source.or_else(|| source_addr(&channel.interface, ping.get_addr()));
+    let frame = match ping.get_addr() {
+        IpAddr::V4(target) => {
+            let source = match source {
+                Some(IpAddr::V4(source)) => source,
+                _ => Ipv4Addr::UNSPECIFIED,
+            };
+            arp_request(mac, source, target)
+        }
+        IpAddr::V6(target) => {
+            let source = match source {
+                Some(IpAddr::V6(source)) => source,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("{} has no ipv6 address", channel.interface.name),
+                    ))
+                }
+            };
+            neighbor_solicit(mac, source, target)
+        }
+    };
+    match channel.tx.send_to(&frame, None) {
+        Some(result) => result.map(|_| frame.len()),
+        None => Err(io::Error::other("datalink channel closed")),
+    }
+}
+
+// the interface's address to send from: one on the target's subnet, or for ipv6
+// the link-local address
+fn source_addr(interface: &NetworkInterface, target: IpAddr) -> Option<IpAddr> {
+    let same_family = || {
+        interface
+            .ips
+            .iter()
+            .map(|ip| ip.ip())
+            .filter(|ip| ip.is_ipv4() == target.is_ipv4())
+    };
+    interface
+        .ips
+        .iter()
+        .find(|ip| ip.contains(target))
+        .map(|ip| ip.ip())
+        .or_else(|| {
+            same_family().find(|ip| match ip {
+                IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+                IpAddr::V4(_) => true,
+            })
+        })
+        .or_else(|| same_family().next())
+}
+
+// a broadcast ARP request for target
+pub fn arp_request(mac: MacAddr, source: Ipv4Addr, target: Ipv4Addr) -> Vec<u8> {
+    let mut vec: Vec<u8> = vec![0; 14 + 28];
+    {
+        let mut arp_packet = MutableArpPacket::new(&mut vec[14..]).unwrap();
+        arp_packet.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_packet.set_protocol_type(EtherTypes::Ipv4);
+        arp_packet.set_hw_addr_len(6);
+        arp_packet.set_proto_addr_len(4);
+        arp_packet.set_operation(ArpOperations::Request);
+        arp_packet.set_sender_hw_addr(mac);
+        arp_packet.set_sender_proto_addr(source);
+        arp_packet.set_target_hw_addr(MacAddr::zero());
+        arp_packet.set_target_proto_addr(target);
+    }
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut vec[..]).unwrap();
+    ethernet_packet.set_destination(MacAddr::broadcast());
+    ethernet_packet.set_source(mac);
+    ethernet_packet.set_ethertype(EtherTypes::Arp);
+    vec
+}
+
+// a Neighbor Solicitation for target, sent to its solicited-node multicast address
+pub fn neighbor_solicit(mac: MacAddr, source: Ipv6Addr, target: Ipv6Addr) -> Vec<u8> {
+    let t = target.octets();
+    let destination = Ipv6Addr::new(
+        0xff02,
+        0,
+        0,
+        0,
+        0,
+        1,
+        0xff00 | u16::from(t[13]),
+        u16::from_be_bytes([t[14], t[15]]),
+    );
+
+    // type, code, checksum, reserved, target, source link-layer address option
+    let mut icmp: Vec<u8> = vec![0; 32];
+    icmp[0] = NEIGHBOR_SOLICIT;
+    icmp[8..24].copy_from_slice(&t);
+    icmp[24] = 1;
+    icmp[25] = 1;
+    icmp[26..32].copy_from_slice(&mac.octets());
+    {
+        let mut icmp_packet = MutableIcmpv6Packet::new(&mut icmp[..]).unwrap();
+        let csum = icmpv6::checksum(&icmp_packet.to_immutable(), &source, &destination);
+        icmp_packet.set_checksum(csum);
+    }
+
+    let mut vec: Vec<u8> = vec![0; 14 + 40 + icmp.len()];
+    vec[54..].copy_from_slice(&icmp);
+    {
+        let mut ip_packet = MutableIpv6Packet::new(&mut vec[14..]).unwrap();
+        ip_packet.set_version(6);
+        ip_packet.set_payload_length(icmp.len() as u16);
+        ip_packet.set_next_header(IpNextHeaderProtocols::Icmpv6);
+        ip_packet.set_hop_limit(255);
+        ip_packet.set_source(source);
+        ip_packet.set_destination(destination);
+    }
+    let d = destination.octets();
+    let mut ethernet_packet = MutableEthernetPacket::new(&mut vec[..]).unwrap();
+    ethernet_packet.set_destination(MacAddr::new(0x33, 0x33, d[12], d[13], d[14], d[15]));
+    ethernet_packet.set_source(mac);
+    ethernet_packet.set_ethertype(EtherTypes::Ipv6);
+    vec
+}
+
+// the address and MAC address an ARP reply or solicited Neighbor Advertisement
+// sent to mac is for
+pub fn parse_reply(frame: &[u8], mac: MacAddr) -> Option<(IpAddr, MacAddr)> {
+    let ethernet_packet = EthernetPacket::new(frame)?;
+    if ethernet_packet.get_destination() != mac {
+        return None;
+    }
+    match ethernet_packet.get_ethertype() {
+        EtherTypes::Arp => {
+            let arp_packet = ArpPacket::new(ethernet_packet.payload())?;
+            if arp_packet.get_operation() != ArpOperations::Reply
+                || arp_packet.get_target_hw_addr() != mac
+            {
+                return None;
+            }
+            Some((
+                IpAddr::V4(arp_packet.get_sender_proto_addr()),
+                arp_packet.get_sender_hw_addr(),
+            ))
+        }
+        EtherTypes::Ipv6 => {
+            let ip_packet = Ipv6Packet::new(ethernet_packet.payload())?;
+            if ip_packet.get_next_header() != IpNextHeaderProtocols::Icmpv6 {
+                return None;
+            }
+            let icmp = ip_packet.payload();
+            if Icmpv6Packet::new(icmp)?.get_icmpv6_type().0 != NEIGHBOR_ADVERT
+                || icmp.len() < 24
+                || icmp[4] & SOLICITED == 0
+            {
+                return None;
+            }
+            let mut target = [0; 16];
+            target.copy_from_slice(&icmp[8..24]);
+            // the target link-layer address option, else whoever sent the frame
+            let mut mac = ethernet_packet.get_source();
+            let mut options = &icmp[24..];
+            while options.len() >= 8 && options[1] != 0 {
+                let len = options[1] as usize * 8;
+                if options[0] == 2 && len >= 8 {
+                    mac = MacAddr::new(
+                        options[2], options[3], options[4], options[5], options[6], options[7],
+                    );
+                }
+                options = &options[len.min(options.len())..];
+            }
+            Some((IpAddr::V6(Ipv6Addr::from(target)), mac))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arp() {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let mut frame = arp_request(
+            mac,
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+        );
+        // not a reply
+        assert_eq!(parse_reply(&frame, mac), None);
+
+        // a gratuitous reply, broadcast
+        let peer = MacAddr::new(2, 0, 0, 0, 0, 2);
+        {
+            let mut arp_packet = MutableArpPacket::new(&mut frame[14..]).unwrap();
+            arp_packet.set_operation(ArpOperations::Reply);
+            arp_packet.set_sender_hw_addr(peer);
+            arp_packet.set_sender_proto_addr("10.0.0.2".parse().unwrap());
+            arp_packet.set_target_hw_addr(MacAddr::broadcast());
+        }
+        assert_eq!(parse_reply(&frame, mac), None);
+
+        {
+            let mut arp_packet = MutableArpPacket::new(&mut frame[14..]).unwrap();
+            arp_packet.set_target_hw_addr(mac);
+        }
+        MutableEthernetPacket::new(&mut frame[..])
+            .unwrap()
+            .set_destination(mac);
+        assert_eq!(
+            parse_reply(&frame, mac),
+            Some(("10.0.0.2".parse().unwrap(), peer))
+        );
+    }
+
+    #[test]
+    fn test_neighbor_solicit() {
+        let mac = MacAddr::new(2, 0, 0, 0, 0, 1);
+        let source = "fe80::1".parse::<Ipv6Addr>().unwrap();
+        let target = "fd00::12:3456".parse::<Ipv6Addr>().unwrap();
+        let mut frame = neighbor_solicit(mac, source, target);
+        let ethernet_packet = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(
+            ethernet_packet.get_destination(),
+            MacAddr::new(0x33, 0x33, 0xff, 0x12, 0x34, 0x56)
+        );
+        let ip_packet = Ipv6Packet::new(ethernet_packet.payload()).unwrap();
+        assert_eq!(
+            ip_packet.get_destination(),
+            "ff02::1:ff12:3456".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(ip_packet.get_hop_limit(), 255);
+        let icmp_packet = Icmpv6Packet::new(ip_packet.payload()).unwrap();
+        assert_eq!(
+            icmp_packet.get_checksum(),
+            icmpv6::checksum(&icmp_packet, &source, &ip_packet.get_destination())
+        );
+
+        // turn it into an unsolicited advertisement carrying a target link-layer
+        // address, then a solicited one sent to us
+        let peer = MacAddr::new(2, 0, 0, 0, 0, 2);
+        frame[54] = NEIGHBOR_ADVERT;
+        frame[54 + 24] = 2;
+        frame[54 + 26..54 + 32].copy_from_slice(&peer.octets());
+        MutableEthernetPacket::new(&mut frame[..])
+            .unwrap()
+            .set_destination(mac);
+        assert_eq!(parse_reply(&frame, mac), None);
+        frame[54 + 4] = SOLICITED;
+        assert_eq!(parse_reply(&frame, mac), Some((IpAddr::V6(target), peer)));
+    }
+}
@@ -0,0 +1,104 @@
+// ICMPv6 Node Information queries (RFC 4620) asking targets for their hostnames.
+// The query's nonce leads with the probe's identifier and sequence number, which
+// the reply echoes back
+use ping::Ping;
+use rand::random;
+use std::net::IpAddr;
+
+// icmpv6 node information query and reply types
+const NI_QUERY: u8 = 139;
+const NI_REPLY: u8 = 140;
+
+// node name query type
+const QTYPE_NODE_NAME: u16 = 2;
+
+// build a node name query for the next sequence number, with the target's address
+// as the subject
+pub fn node_name_query(ping: &mut Ping) -> Vec<u8> {
+    let subject = match ping.get_addr() {
+        IpAddr::V6(addr) => addr.octets(),
+        IpAddr::V4(addr) => addr.to_ipv6_mapped().octets(),
+    };
+    // type, code (subject is an ipv6 address), checksum, qtype, flags, nonce, subject
+    let mut vec: Vec<u8> = vec![0; 16 + 16];
+    vec[0] = NI_QUERY;
+    vec[4..6].copy_from_slice(&QTYPE_NODE_NAME.to_be_bytes());
+    vec[8..10].copy_from_slice(&ping.get_identifier().to_be_bytes());
+    vec[10..12].copy_from_slice(&ping.increment_sequence_number().to_be_bytes());
+    vec[12..16].copy_from_slice(&random::<u32>().to_be_bytes());
+    vec[16..].copy_from_slice(&subject);
+
+    // Note: ICMPv6 checksum always calculated by the kernel, see RFC 3542
+    vec
+}
+
+// the identifier, sequence number and names of a successful node name reply
+pub fn parse_reply(packet: &[u8]) -> Option<(u16, u16, Vec<String>)> {
+    if packet.len() < 16
+        || packet[0] != NI_REPLY
+        || packet[1] != 0
+        || u16::from_be_bytes([packet[4], packet[5]]) != QTYPE_NODE_NAME
+    {
+        return None;
+    }
+    let identifier = u16::from_be_bytes([packet[8], packet[9]]);
+    let sequence_number = u16::from_be_bytes([packet[10], packet[11]]);
+    // a ttl, then names in dns wire format without compression
+    let data = packet.get(20..).unwrap_or(&[]);
+    Some((identifier, sequence_number, parse_names(data)))
+}
+
+fn parse_names(mut data: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    while !data.is_empty() {
+        let mut labels = Vec::new();
+        while let Some(&len) = data.first() {
+            let len = len as usize;
+            data = &data[1..];
+            if len == 0 || len > data.len() {
+                break;
+            }
+            labels.push(String::from_utf8_lossy(&data[..len]).into_owned());
+            data = &data[len..];
+        }
+        if !labels.is_empty() {
+            names.push(labels.join("."));
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_name_query() {
+        let mut ping = Ping::new("fe80::1".parse().unwrap());
+        let query = node_name_query(&mut ping);
+        assert_eq!(query.len(), 32);
+        assert_eq!(&query[..2], &[NI_QUERY, 0]);
+        assert_eq!(&query[4..6], &[0, 2]);
+        assert_eq!(&query[10..12], &[0, 1]);
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let mut packet = vec![NI_REPLY, 0, 0, 0, 0, 2, 0, 0, 0x12, 0x34, 0, 7, 0, 0, 0, 0];
+        packet.extend_from_slice(&[0, 0, 0, 0]);
+        packet.extend_from_slice(b"\x04host\x07example\x00");
+        packet.extend_from_slice(b"\x05other\x00\x00");
+        assert_eq!(
+            parse_reply(&packet),
+            Some((
+                0x1234,
+                7,
+                vec!["host.example".to_string(), "other".to_string()]
+            ))
+        );
+
+        // refused
+        packet[1] = 1;
+        assert_eq!(parse_reply(&packet), None);
+    }
+}
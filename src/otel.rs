@@ -0,0 +1,335 @@
+// OpenTelemetry instrumentation built from the results send_pings produces: rtt
+// histograms and reply and loss counters per target, and a span per round with a
+// child span per probe.  The spans are synthesized from the results once a round
+// completes, not opened around the round in send_pings, so they carry the round's
+// recorded times but aren't the current span while it runs.  Probe spans are timed
+// by the reply's send and receive times or, for idle targets, the whole round
+use opentelemetry::global::{self, BoxedTracer};
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::trace::{Span, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use sink::ResultHandler;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+use target::Probe;
+use PingResult;
+
+// a probe of the round in progress, waiting for the round's span
+struct Probed {
+    addr: IpAddr,
+    probe: Probe,
+    outcome: &'static str,
+    rtt: Option<Duration>,
+    // when the probe went out and its reply came in
+    times: Option<(SystemTime, SystemTime)>,
+}
+
+// records PingResults to OpenTelemetry meters and spans, either through observe or
+// as the pinger's ResultHandler
+pub struct OtelRecorder<T: Tracer = BoxedTracer> {
+    tracer: T,
+    rtt: Histogram<f64>,
+    replies: Counter<u64>,
+    lost: Counter<u64>,
+    // probes of the round in progress and its round_id.  Probes left over from a
+    // round whose RoundComplete never came are dropped when the next one starts
+    round: Vec<Probed>,
+    round_id: u64,
+}
+
+impl OtelRecorder<BoxedTracer> {
+    // record to the globally registered meter and tracer providers
+    pub fn global() -> OtelRecorder<BoxedTracer> {
+        OtelRecorder::new(&global::meter("fastping-rs"), global::tracer("fastping-rs"))
+    }
+}
+
+impl<T: Tracer> OtelRecorder<T>
+where
+    T::Span: Send + Sync + 'static,
+{
+    pub fn new(meter: &Meter, tracer: T) -> OtelRecorder<T> {
+        OtelRecorder {
+            tracer,
+            rtt: meter
+                .f64_histogram("fastping.rtt")
+                .with_unit("s")
+                .with_description("Round trip time of replies")
+                .build(),
+            replies: meter
+                .u64_counter("fastping.replies")
+                .with_description("Replies received")
+                .build(),
+            lost: meter
+                .u64_counter("fastping.lost")
+                .with_description("Rounds a target didn't answer")
+                .build(),
+            round: Vec::new(),
+            round_id: 0,
+        }
+    }
+
+    // record a result, closing the round's spans on RoundComplete
+    pub fn observe(&mut self, result: &PingResult) {
+        let round_id = match *result {
+            PingResult::Idle { round_id, .. }
+            | PingResult::Receive { round_id, .. }
+            | PingResult::Timestamp { round_id, .. }
+            | PingResult::Neighbor { round_id, .. }
+            | PingResult::NodeInfo { round_id, .. }
+            | PingResult::Discovered { round_id, .. }
+            | PingResult::StateChanged { round_id, .. }
+            | PingResult::RoundComplete { round_id, .. } => round_id,
+        };
+        if round_id != self.round_id {
+            if !self.round.is_empty() {
+                debug!(
+                    "Dropping {} probe spans of round {}, which never completed",
+                    self.round.len(),
+                    self.round_id
+                );
+                self.round.clear();
+            }
+            self.round_id = round_id;
+        }
+        match *result {
+            PingResult::Receive {
+                addr,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::Timestamp {
+                addr,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                rtt,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                rtt,
+                ref reply,
+                ..
+            } => {
+                let attributes = attributes(addr, reply.probe);
+                self.rtt.record(rtt.as_secs_f64(), &attributes);
+                self.replies.add(1, &attributes);
+                self.round.push(Probed {
+                    addr,
+                    probe: reply.probe,
+                    outcome: "reply",
+                    rtt: Some(rtt),
+                    times: Some((reply.sent_at, reply.received_at)),
+                });
+            }
+            PingResult::Discovered {
+                addr,
+                rtt,
+                ref reply,
+                ..
+            } => self.round.push(Probed {
+                addr,
+                probe: reply.probe,
+                outcome: "discovered",
+                rtt: Some(rtt),
+                times: Some((reply.sent_at, reply.received_at)),
+            }),
+            PingResult::Idle { addr, probe, .. } => {
+                self.lost.add(1, &attributes(addr, probe));
+                self.round.push(Probed {
+                    addr,
+                    probe,
+                    outcome: "timeout",
+                    rtt: None,
+                    times: None,
+                });
+            }
+            PingResult::RoundComplete {
+                round_id,
+                started_at,
+                duration,
+                sent,
+                received,
+                idle,
+            } => {
+                let ended_at = started_at + duration;
+                let round = self
+                    .tracer
+                    .span_builder("fastping.round")
+                    .with_start_time(started_at)
+                    .with_attributes(vec![
+                        KeyValue::new("fastping.round_id", round_id as i64),
+                        KeyValue::new("fastping.sent", sent as i64),
+                        KeyValue::new("fastping.received", received as i64),
+                        KeyValue::new("fastping.idle", idle as i64),
+                    ])
+                    .start(&self.tracer);
+                let cx = Context::current_with_span(round);
+                for probed in self.round.drain(..) {
+                    let (start, end) = probed.times.unwrap_or((started_at, ended_at));
+                    let mut attributes = attributes(probed.addr, probed.probe);
+                    attributes.push(KeyValue::new("fastping.outcome", probed.outcome));
+                    if let Some(rtt) = probed.rtt {
+                        attributes.push(KeyValue::new("fastping.rtt_us", rtt.as_micros() as i64));
+                    }
+                    self.tracer
+                        .span_builder("fastping.probe")
+                        .with_start_time(start)
+                        .with_attributes(attributes)
+                        .start_with_context(&self.tracer, &cx)
+                        .end_with_timestamp(end);
+                }
+                cx.span().end_with_timestamp(ended_at);
+            }
+            PingResult::StateChanged { .. } => {}
+        }
+    }
+}
+
+impl<T: Tracer + Send> ResultHandler for OtelRecorder<T>
+where
+    T::Span: Send + Sync + 'static,
+{
+    fn on_receive(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_round_complete(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+}
+
+fn attributes(addr: IpAddr, probe: Probe) -> Vec<KeyValue> {
+    vec![
+        KeyValue::new("fastping.target", addr.to_string()),
+        KeyValue::new("fastping.probe", probe.to_string()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+    use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use ping::PingReply;
+    use socket::Binding;
+
+    #[test]
+    fn test_recorder() {
+        let metric_exporter = InMemoryMetricExporter::default();
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metric_exporter.clone()).build())
+            .build();
+        let span_exporter = InMemorySpanExporter::default();
+        let tracer_provider = SdkTracerProvider::builder()
+            .with_simple_exporter(span_exporter.clone())
+            .build();
+        let mut recorder = OtelRecorder::new(
+            &meter_provider.meter("test"),
+            tracer_provider.tracer("test"),
+        );
+
+        let started_at = SystemTime::now();
+        let reply = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let idle = "192.0.2.2".parse::<IpAddr>().unwrap();
+        // a round that never completed leaves no spans behind
+        recorder.observe(&PingResult::Idle {
+            addr: idle,
+            binding: Binding::default(),
+            attempts: 1,
+            round_id: 0,
+            probe: Probe::Tcp(443),
+        });
+        recorder.observe(&PingResult::Receive {
+            addr: reply,
+            rtt: Duration::from_millis(3),
+            binding: Binding::default(),
+            ttl: Some(64),
+            size: 24,
+            hops: Some(0),
+            route: None,
+            attempts: 1,
+            round_id: 1,
+            reply: PingReply {
+                sequence_number: 1,
+                identifier: 7,
+                size: 24,
+                ttl: Some(64),
+                sent_at: started_at,
+                received_at: started_at + Duration::from_millis(3),
+                probe: Probe::Echo,
+            },
+        });
+        recorder.observe(&PingResult::Idle {
+            addr: idle,
+            binding: Binding::default(),
+            attempts: 1,
+            round_id: 1,
+            probe: Probe::Tcp(443),
+        });
+        recorder.observe(&PingResult::RoundComplete {
+            round_id: 1,
+            started_at,
+            duration: Duration::from_secs(1),
+            sent: 2,
+            received: 1,
+            idle: 1,
+        });
+
+        let spans = span_exporter.get_finished_spans().unwrap();
+        assert_eq!(spans.len(), 3);
+        let round = spans.iter().find(|s| s.name == "fastping.round").unwrap();
+        assert_eq!(round.end_time, started_at + Duration::from_secs(1));
+        let probes: Vec<_> = spans
+            .iter()
+            .filter(|s| s.name == "fastping.probe")
+            .collect();
+        assert!(probes
+            .iter()
+            .all(|s| s.parent_span_id == round.span_context.span_id()));
+        let timeout = probes
+            .iter()
+            .find(|s| {
+                s.attributes
+                    .contains(&KeyValue::new("fastping.outcome", "timeout"))
+            })
+            .unwrap();
+        assert!(timeout
+            .attributes
+            .contains(&KeyValue::new("fastping.target", "192.0.2.2")));
+        assert!(timeout
+            .attributes
+            .contains(&KeyValue::new("fastping.probe", "tcp:443")));
+
+        meter_provider.force_flush().unwrap();
+        let metrics = metric_exporter.get_finished_metrics().unwrap();
+        let metrics: Vec<_> = metrics
+            .iter()
+            .flat_map(|resource| resource.scope_metrics())
+            .flat_map(|scope| scope.metrics())
+            .collect();
+        let rtt = metrics.iter().find(|m| m.name() == "fastping.rtt").unwrap();
+        match rtt.data() {
+            AggregatedMetrics::F64(MetricData::Histogram(histogram)) => {
+                let point = histogram.data_points().next().unwrap();
+                assert_eq!(point.count(), 1);
+            }
+            _ => panic!("expected an rtt histogram"),
+        }
+        assert!(metrics.iter().any(|m| m.name() == "fastping.lost"));
+        assert!(metrics.iter().any(|m| m.name() == "fastping.replies"));
+    }
+}
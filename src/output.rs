@@ -0,0 +1,464 @@
+// Writers for scripting around the pinger: newline-delimited JSON of results and
+// stats snapshots, for jq or a log pipeline, and CSV with a row per result or per
+// target, for spreadsheets and pandas.  Both can be the pinger's ResultHandler
+use serde::Serialize;
+use sink::ResultHandler;
+use socket::Binding;
+use stats::StatsSnapshot;
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use PingResult;
+
+// writes each value as a line of JSON
+pub struct NdjsonWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> NdjsonWriter<W> {
+    pub fn new(writer: W) -> NdjsonWriter<W> {
+        NdjsonWriter { writer }
+    }
+
+    // write a PingResult, StatsSnapshot or anything else serializable as one line
+    pub fn write<T: Serialize>(&mut self, value: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, value)?;
+        self.writer.write_all(b"\n")
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> ResultHandler for NdjsonWriter<W> {
+    fn on_receive(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    fn on_event(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    // flushed once the round's results are all written, so a pipe sees whole rounds
+    fn on_round_complete(&mut self, result: PingResult) {
+        write_or_log(self.write(&result).and_then(|_| self.flush()));
+    }
+}
+
+const RESULT_HEADER: &str = "type,round_id,addr,source,interface,probe,time,rtt_ms,ttl,size,attempts,from,to,sent,received,idle";
+
+// writes a header and then a row per result.  Columns that don't apply to a result
+// are left empty, as are the source and interface of the default binding; time is
+// when a reply arrived or a round started, in seconds since the unix epoch.  Fields
+// with a comma, quote or line break are quoted
+pub struct CsvWriter<W: Write> {
+    writer: W,
+    header: bool,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(writer: W) -> CsvWriter<W> {
+        CsvWriter {
+            writer,
+            header: false,
+        }
+    }
+
+    pub fn write(&mut self, result: &PingResult) -> io::Result<()> {
+        if !self.header {
+            writeln!(self.writer, "{}", RESULT_HEADER)?;
+            self.header = true;
+        }
+        let mut row = vec![String::new(); 16];
+        match *result {
+            PingResult::Receive {
+                addr,
+                rtt,
+                ref binding,
+                size,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            } => {
+                row[0] = "receive".to_string();
+                row[1] = round_id.to_string();
+                row[2] = addr.to_string();
+                let (source, interface) = binding_fields(binding);
+                row[3] = source;
+                row[4] = interface;
+                row[5] = reply.probe.to_string();
+                row[6] = seconds(reply.received_at);
+                row[7] = millis(rtt);
+                row[8] = reply.ttl.map_or_else(String::new, |ttl| ttl.to_string());
+                row[9] = size.to_string();
+                row[10] = attempts.to_string();
+            }
+            PingResult::Timestamp {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            } => {
+                row[0] = kind(result).to_string();
+                row[1] = round_id.to_string();
+                row[2] = addr.to_string();
+                let (source, interface) = binding_fields(binding);
+                row[3] = source;
+                row[4] = interface;
+                row[5] = reply.probe.to_string();
+                row[6] = seconds(reply.received_at);
+                row[7] = millis(rtt);
+                row[8] = reply.ttl.map_or_else(String::new, |ttl| ttl.to_string());
+                row[9] = reply.size.to_string();
+                row[10] = attempts.to_string();
+            }
+            PingResult::Discovered {
+                addr,
+                rtt,
+                ref binding,
+                round_id,
+                ref reply,
+                ..
+            } => {
+                row[0] = "discovered".to_string();
+                row[1] = round_id.to_string();
+                row[2] = addr.to_string();
+                let (source, interface) = binding_fields(binding);
+                row[3] = source;
+                row[4] = interface;
+                row[5] = reply.probe.to_string();
+                row[6] = seconds(reply.received_at);
+                row[7] = millis(rtt);
+                row[8] = reply.ttl.map_or_else(String::new, |ttl| ttl.to_string());
+                row[9] = reply.size.to_string();
+            }
+            PingResult::Idle {
+                addr,
+                ref binding,
+                attempts,
+                round_id,
+                probe,
+                ..
+            } => {
+                row[0] = "idle".to_string();
+                row[1] = round_id.to_string();
+                row[2] = addr.to_string();
+                let (source, interface) = binding_fields(binding);
+                row[3] = source;
+                row[4] = interface;
+                row[5] = probe.to_string();
+                row[10] = attempts.to_string();
+            }
+            PingResult::StateChanged {
+                addr,
+                ref binding,
+                from,
+                to,
+                round_id,
+                probe,
+                ..
+            } => {
+                row[0] = "state_changed".to_string();
+                row[1] = round_id.to_string();
+                row[2] = addr.to_string();
+                let (source, interface) = binding_fields(binding);
+                row[3] = source;
+                row[4] = interface;
+                row[5] = probe.to_string();
+                row[11] = format!("{:?}", from);
+                row[12] = format!("{:?}", to);
+            }
+            PingResult::RoundComplete {
+                round_id,
+                started_at,
+                duration,
+                sent,
+                received,
+                idle,
+            } => {
+                row[0] = "round_complete".to_string();
+                row[1] = round_id.to_string();
+                row[6] = seconds(started_at);
+                row[7] = millis(duration);
+                row[13] = sent.to_string();
+                row[14] = received.to_string();
+                row[15] = idle.to_string();
+            }
+        }
+        let row = row.iter().map(|field| quote(field)).collect::<Vec<_>>();
+        writeln!(self.writer, "{}", row.join(","))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> ResultHandler for CsvWriter<W> {
+    fn on_receive(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    fn on_event(&mut self, result: PingResult) {
+        write_or_log(self.write(&result));
+    }
+
+    fn on_round_complete(&mut self, result: PingResult) {
+        write_or_log(self.write(&result).and_then(|_| self.flush()));
+    }
+}
+
+impl StatsSnapshot {
+    // write the snapshot as CSV, a header and then a row per target with rtts in
+    // milliseconds
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writeln!(
+            writer,
+            "addr,source,interface,rounds,received,lost,loss,probes,min_rtt_ms,avg_rtt_ms,max_rtt_ms,last_rtt_ms,last_seen,state"
+        )?;
+        let optional = |rtt: Option<Duration>| rtt.map_or_else(String::new, millis);
+        for target in &self.targets {
+            let (source, interface) = binding_fields(&target.binding);
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                target.addr,
+                source,
+                quote(&interface),
+                target.rounds,
+                target.received,
+                target.lost,
+                target.loss,
+                target.probes,
+                optional(target.min_rtt),
+                optional(target.avg_rtt),
+                optional(target.max_rtt),
+                optional(target.last_rtt),
+                target.last_seen.map_or_else(String::new, seconds),
+                target
+                    .state
+                    .map_or_else(String::new, |state| format!("{:?}", state))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// the source and interface columns of a binding, empty when unset
+fn binding_fields(binding: &Binding) -> (String, String) {
+    (
+        binding
+            .source
+            .map_or_else(String::new, |source| source.to_string()),
+        binding.interface.clone().unwrap_or_default(),
+    )
+}
+
+// a CSV field, quoted if it has a comma, quote or line break
+fn quote(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn kind(result: &PingResult) -> &'static str {
+    match *result {
+        PingResult::Timestamp { .. } => "timestamp",
+        PingResult::Neighbor { .. } => "neighbor",
+        _ => "nodeinfo",
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+fn seconds(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    format!("{:.6}", since.as_secs_f64())
+}
+
+fn write_or_log(written: io::Result<()>) {
+    if let Err(e) = written {
+        error!("Error writing ping result: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ping::PingReply;
+    use serde_json::Value;
+    use socket::Binding;
+    use stats::Statistics;
+    use std::net::IpAddr;
+    use target::Probe;
+
+    fn results() -> Vec<PingResult> {
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let started_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        vec![
+            PingResult::Receive {
+                addr,
+                rtt: Duration::from_micros(1500),
+                binding: Binding::default(),
+                ttl: Some(64),
+                size: 64,
+                hops: Some(0),
+                route: None,
+                attempts: 1,
+                round_id: 1,
+                reply: PingReply {
+                    sequence_number: 1,
+                    identifier: 7,
+                    size: 64,
+                    ttl: Some(64),
+                    sent_at: started_at,
+                    received_at: started_at + Duration::from_micros(1500),
+                    probe: Probe::Echo,
+                },
+            },
+            PingResult::Idle {
+                addr: "192.0.2.2".parse().unwrap(),
+                binding: Binding {
+                    source: Some("192.0.2.100".parse().unwrap()),
+                    interface: Some("lab,1".to_string()),
+                },
+                attempts: 2,
+                round_id: 1,
+                probe: Probe::Tcp(443),
+            },
+            PingResult::RoundComplete {
+                round_id: 1,
+                started_at,
+                duration: Duration::from_secs(1),
+                sent: 3,
+                received: 1,
+                idle: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let mut writer = NdjsonWriter::new(Vec::new());
+        for result in results() {
+            writer.on_receive(result);
+        }
+        let stats = Statistics::new();
+        for result in results() {
+            stats.observe(&result);
+        }
+        writer.write(&stats.snapshot()).unwrap();
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["type"], "Receive");
+        assert_eq!(lines[0]["addr"], "192.0.2.1");
+        assert_eq!(lines[0]["reply"]["identifier"], 7);
+        assert_eq!(lines[1]["type"], "Idle");
+        assert_eq!(lines[1]["probe"]["Tcp"], 443);
+        assert_eq!(lines[2]["type"], "RoundComplete");
+        assert_eq!(lines[2]["received"], 1);
+        assert_eq!(lines[3]["round_id"], 1);
+        assert_eq!(lines[3]["targets"][1]["loss"], 100.0);
+
+        // results read back as they were written
+        let line = output.lines().next().unwrap();
+        let result: PingResult = serde_json::from_str(line).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), line);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("eth0"), "eth0");
+        assert_eq!(quote("a,b"), "\"a,b\"");
+        assert_eq!(quote("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_csv() {
+        let mut writer = CsvWriter::new(Vec::new());
+        for result in results() {
+            writer.write(&result).unwrap();
+        }
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], RESULT_HEADER);
+        assert_eq!(
+            lines[1],
+            "receive,1,192.0.2.1,,,echo,1000000.001500,1.500,64,64,1,,,,,"
+        );
+        assert_eq!(
+            lines[2],
+            "idle,1,192.0.2.2,192.0.2.100,\"lab,1\",tcp:443,,,,,2,,,,,"
+        );
+        assert_eq!(
+            lines[3],
+            "round_complete,1,,,,,1000000.000000,1000.000,,,,,,3,1,1"
+        );
+
+        let stats = Statistics::new();
+        for result in results() {
+            stats.observe(&result);
+        }
+        let mut output = Vec::new();
+        stats.snapshot().write_csv(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[1],
+            "192.0.2.1,,,1,1,0,0,1,1.500,1.500,1.500,1.500,1000000.001500,"
+        );
+        assert_eq!(
+            lines[2],
+            "192.0.2.2,192.0.2.100,\"lab,1\",1,0,1,100,2,,,,,,"
+        );
+    }
+}
@@ -1,29 +1,145 @@
+use capture::Capture;
+use events;
+#[cfg(feature = "layer3")]
+use layer3;
+#[cfg(feature = "layer3")]
+use layer3::IpHeader;
+use neighbor;
+use nodeinfo::node_name_query;
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::icmpv6::Icmpv6Packet;
 use pnet::packet::Packet;
 use pnet::packet::{icmp, icmpv6};
 use pnet::transport::TransportSender;
 use pnet::util;
+use pnet::util::MacAddr;
 use rand::random;
-use std::collections::BTreeMap;
-use std::net::IpAddr;
+use route::{RecordMode, RecordedRoute};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sink::ResultSink;
+use socket::{Binding, Senders};
+use state::StateTracker;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::net::{IpAddr, UdpSocket};
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use target::Probe;
+use tcp;
+use timestamp::{timestamp_request, TimestampReply};
+use udp;
 use PingResult;
 
+// targets are keyed by address and binding so the same address can be pinged
+// via several interfaces or source addresses, and apart from the rest when they
+// carry a layer 3 header of their own, since those don't go through the binding
+pub type TargetKey = (IpAddr, Binding, bool);
+
+// how often to resend to a target that hasn't answered within a round.  Attempt n
+// (from 0) waits timeout * backoff^n for a reply, fping -r/-t/-B style
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    // wait for the first attempt, None for the pinger's max_rtt
+    pub timeout: Option<Duration>,
+    pub backoff: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            retries: 0,
+            timeout: None,
+            backoff: 1.5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // how long to wait for a reply to the given attempt
+    pub fn timeout(&self, max_rtt: Duration, attempt: u32) -> Duration {
+        self.timeout
+            .unwrap_or(max_rtt)
+            .mul_f64(self.backoff.powi(attempt as i32))
+    }
+}
+
 pub struct Ping {
     addr: IpAddr,
     identifier: u16,
     sequence_number: u16,
     pub seen: bool,
+    // ttl (hop limit) and tos (traffic class) to send with, None for the kernel default
+    pub ttl: Option<u8>,
+    pub tos: Option<u8>,
+    // ipv4 record route or timestamp option to send with
+    pub record: Option<RecordMode>,
+    pub probe: Probe,
+    // socket udp probes are sent from, its local port is the identifier
+    pub udp: Option<UdpSocket>,
+    // probing a multicast or broadcast address, and who answered it this round
+    pub discover: bool,
+    pub responders: BTreeSet<IpAddr>,
+    // probes sent this round, and each that went out, any of which a reply may answer
+    pub attempts: u32,
+    pub sent: Vec<Attempt>,
+    // up/down tracking, when the target reports state changes
+    pub state: Option<StateTracker>,
+    // hand-crafted ip header to send with instead of letting the kernel write one
+    #[cfg(feature = "layer3")]
+    pub header: Option<IpHeader>,
+}
+
+// details of a reply reported alongside its result.  Marked non_exhaustive so
+// fields can be added without breaking consumers
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PingReply {
+    pub sequence_number: u16,
+    pub identifier: u16,
+    // bytes received, 0 for tcp probes
+    pub size: usize,
+    // reply ttl (hop limit), None where the socket doesn't report it
+    pub ttl: Option<u8>,
+    // wall clock times the answered probe went out and its reply came in
+    pub sent_at: SystemTime,
+    pub received_at: SystemTime,
+    pub probe: Probe,
+}
+
+// a probe that went out to a target this round
+#[derive(Clone, Copy)]
+pub struct Attempt {
+    pub sequence_number: u16,
+    // when it was sent, by the monotonic clock replies are timed with and the wall
+    // clock reported in PingReply
+    pub sent: Instant,
+    pub sent_at: SystemTime,
 }
 
 pub struct ReceivedPing {
     pub addr: IpAddr,
+    // the binding the reply came in through, where the listener knows it.  Replies
+    // read off the shared icmp sockets are matched by identifier instead
+    pub binding: Option<Binding>,
     pub identifier: u16,
     pub sequence_number: u16,
-    pub rtt: Duration,
+    // when the reply came in, the probe it answers is timed from its send
+    pub arrived: Instant,
+    pub ttl: Option<u8>,
+    pub size: usize,
+    pub route: Option<RecordedRoute>,
+    pub timestamps: Option<TimestampReply>,
+    pub mac: Option<MacAddr>,
+    pub names: Option<Vec<String>>,
+    pub received_at: SystemTime,
 }
 
+#[allow(clippy::needless_return)]
 impl Ping {
     pub fn new(addr: IpAddr) -> Ping {
         Ping {
@@ -31,6 +147,18 @@ impl Ping {
             identifier: random::<u16>(),
             sequence_number: 0,
             seen: false,
+            ttl: None,
+            tos: None,
+            record: None,
+            probe: Probe::Echo,
+            udp: None,
+            discover: false,
+            responders: BTreeSet::new(),
+            attempts: 0,
+            sent: Vec::new(),
+            state: None,
+            #[cfg(feature = "layer3")]
+            header: None,
         }
     }
 
@@ -42,6 +170,10 @@ impl Ping {
         return self.identifier;
     }
 
+    pub fn set_identifier(&mut self, identifier: u16) {
+        self.identifier = identifier;
+    }
+
     pub fn get_sequence_number(&self) -> u16 {
         return self.sequence_number;
     }
@@ -50,137 +182,579 @@ impl Ping {
         self.sequence_number += 1;
         return self.sequence_number;
     }
+
+    // whether another ping for the same target would send the same probes
+    pub fn same_options(&self, other: &Ping) -> bool {
+        #[cfg(feature = "layer3")]
+        {
+            if self.header != other.header {
+                return false;
+            }
+        }
+        (self.ttl, self.tos, self.record, self.probe, self.discover)
+            == (
+                other.ttl,
+                other.tos,
+                other.record,
+                other.probe,
+                other.discover,
+            )
+    }
+}
+
+// give a new target an identifier no other target for its address has, so replies
+// read off the shared sockets match only one of them.  Udp probes keep their port
+pub fn unique_identifier(targets: &BTreeMap<TargetKey, Ping>, key: &TargetKey, ping: &mut Ping) {
+    if let Probe::Udp(_) = ping.probe {
+        return;
+    }
+    while targets
+        .range((key.0, Binding::default(), false)..)
+        .take_while(|&(&(addr, _, _), _)| addr == key.0)
+        .any(|(other, other_ping)| {
+            other != key && other_ping.get_identifier() == ping.get_identifier()
+        })
+    {
+        ping.set_identifier(random::<u16>());
+    }
+}
+
+// estimate how many hops a reply crossed from its ttl, assuming the sender started
+// from the nearest common initial ttl (32, 64, 128 or 255) at or above it
+pub fn infer_hops(ttl: u8) -> u8 {
+    let initial = [32, 64, 128, 255]
+        .iter()
+        .find(|&&initial| initial >= ttl)
+        .unwrap_or(&255);
+    initial - ttl
+}
+
+// build an icmp echo request of the given size for the next sequence number
+pub fn echo_request(ping: &mut Ping, size: usize) -> Vec<u8> {
+    // Allocate enough space for a new packet
+    let mut vec: Vec<u8> = vec![0; size];
+    {
+        let mut echo_packet =
+            icmp::echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
+        echo_packet.set_sequence_number(ping.increment_sequence_number());
+        echo_packet.set_identifier(ping.get_identifier());
+        echo_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+
+        let csum = util::checksum(echo_packet.packet(), 1);
+        echo_packet.set_checksum(csum);
+    }
+    vec
 }
 
+// build an icmpv6 echo request of the given size for the next sequence number
+pub fn echo_requestv6(ping: &mut Ping, size: usize) -> Vec<u8> {
+    // Allocate enough space for a new packet
+    let mut vec: Vec<u8> = vec![0; size];
+    {
+        let mut echo_packet =
+            icmpv6::echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
+        echo_packet.set_sequence_number(ping.increment_sequence_number());
+        echo_packet.set_identifier(ping.get_identifier());
+        echo_packet.set_icmpv6_type(icmpv6::Icmpv6Types::EchoRequest);
+
+        // Note: ICMPv6 checksum always calculated by the kernel, see RFC 3542
+    }
+    vec
+}
+
+// send an echo request, copying it to the capture if there is one
 fn send_echo(
     tx: &mut TransportSender,
     ping: &mut Ping,
     size: usize,
+    capture: Option<&Mutex<Capture>>,
+    source: Option<IpAddr>,
 ) -> Result<usize, std::io::Error> {
-    // Allocate enough space for a new packet
-    let mut vec: Vec<u8> = vec![0; size];
-
-    let mut echo_packet = icmp::echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-    echo_packet.set_sequence_number(ping.increment_sequence_number());
-    echo_packet.set_identifier(ping.get_identifier());
-    echo_packet.set_icmp_type(icmp::IcmpTypes::EchoRequest);
+    let vec = echo_request(ping, size);
+    let sent = tx.send_to(IcmpPacket::new(&vec[..]).unwrap(), ping.get_addr())?;
+    if let Some(capture) = capture {
+        capture
+            .lock()
+            .unwrap()
+            .sent(source, ping.get_addr(), ping.ttl, ping.tos, &vec);
+    }
+    Ok(sent)
+}
 
-    let csum = util::checksum(echo_packet.packet(), 1);
-    echo_packet.set_checksum(csum);
+fn send_timestamp(tx: &mut TransportSender, ping: &mut Ping) -> Result<usize, std::io::Error> {
+    let vec = timestamp_request(ping);
+    tx.send_to(IcmpPacket::new(&vec[..]).unwrap(), ping.get_addr())
+}
 
-    tx.send_to(echo_packet, ping.get_addr())
+fn send_node_info(tx: &mut TransportSender, ping: &mut Ping) -> Result<usize, std::io::Error> {
+    let vec = node_name_query(ping);
+    tx.send_to(Icmpv6Packet::new(&vec[..]).unwrap(), ping.get_addr())
 }
 
 fn send_echov6(
     tx: &mut TransportSender,
     ping: &mut Ping,
     size: usize,
+    capture: Option<&Mutex<Capture>>,
+    source: Option<IpAddr>,
 ) -> Result<usize, std::io::Error> {
-    // Allocate enough space for a new packet
-    let mut vec: Vec<u8> = vec![0; size];
+    let vec = echo_requestv6(ping, size);
+    let sent = tx.send_to(Icmpv6Packet::new(&vec[..]).unwrap(), ping.get_addr())?;
+    if let Some(capture) = capture {
+        capture
+            .lock()
+            .unwrap()
+            .sent(source, ping.get_addr(), ping.ttl, ping.tos, &vec);
+    }
+    Ok(sent)
+}
+
+// send the next probe to a target through the socket for its binding.  Tcp probes
+// get a socket of their own and report back on thread_tx once answered, udp
+// probes go out of the target's own socket
+fn send_ping(
+    senders: &mut Senders,
+    binding: &Binding,
+    ping: &mut Ping,
+    size: usize,
+    timeout: Duration,
+    thread_tx: &Sender<ReceivedPing>,
+) -> Result<usize, std::io::Error> {
+    let (mut sent_instant, mut sent_at) = (Instant::now(), SystemTime::now());
+    let sent = match ping.probe {
+        Probe::Tcp(port) => {
+            if senders.connector.is_none() {
+                senders.connector = Some(tcp::Connector::new()?);
+            }
+            // timed from the connect, which may run in another network namespace
+            let (started, started_at) = tcp::send_probe(
+                senders.netns.as_deref(),
+                senders.connector.as_mut().unwrap(),
+                binding,
+                ping,
+                port,
+                timeout,
+                thread_tx,
+            )?;
+            sent_instant = started;
+            sent_at = started_at;
+            Ok(0)
+        }
+        Probe::Neighbor => match senders.neighbor.get_mut(binding) {
+            Some(channel) => neighbor::send_probe(channel, ping, binding.source),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no datalink channel open for {:?}", binding),
+            )),
+        },
+        Probe::Udp(port) => udp::send_probe(ping, port, size),
+        Probe::Echo | Probe::Timestamp | Probe::NodeInfo => send_icmp(senders, binding, ping, size),
+    }?;
+    ping.sent.push(Attempt {
+        sequence_number: ping.get_sequence_number(),
+        sent: sent_instant,
+        sent_at,
+    });
+    Ok(sent)
+}
 
-    let mut echo_packet =
-        icmpv6::echo_request::MutableEchoRequestPacket::new(&mut vec[..]).unwrap();
-    echo_packet.set_sequence_number(ping.increment_sequence_number());
-    echo_packet.set_identifier(ping.get_identifier());
-    echo_packet.set_icmpv6_type(icmpv6::Icmpv6Types::EchoRequest);
+// send the next icmp probe (echo, timestamp or node information) to a target
+fn send_icmp(
+    senders: &mut Senders,
+    binding: &Binding,
+    ping: &mut Ping,
+    size: usize,
+) -> Result<usize, std::io::Error> {
+    #[cfg(feature = "layer3")]
+    {
+        if ping.header.is_some() {
+            return layer3::send_echo(senders, ping, size);
+        }
+    }
 
-    // Note: ICMPv6 checksum always calculated by the kernel, see RFC 3542
+    let addr = ping.get_addr();
+    let capture = senders.capture.as_deref();
+    let channels = if addr.is_ipv4() {
+        &mut senders.tx
+    } else {
+        &mut senders.txv6
+    };
+    match channels.get_mut(binding) {
+        Some(channel) => {
+            channel.apply(ping.ttl, ping.tos, ping.record)?;
+            match ping.probe {
+                Probe::Timestamp => send_timestamp(&mut channel.tx, ping),
+                Probe::NodeInfo => send_node_info(&mut channel.tx, ping),
+                _ if addr.is_ipv4() => {
+                    send_echo(&mut channel.tx, ping, size, capture, binding.source)
+                }
+                _ => send_echov6(&mut channel.tx, ping, size, capture, binding.source),
+            }
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no socket open for {:?}", binding),
+        )),
+    }
+}
 
-    tx.send_to(echo_packet, ping.get_addr())
+// feed a target's round into its state tracker and build the StateChanged to
+// report, if its state changed
+fn update_state(
+    addr: IpAddr,
+    binding: &Binding,
+    ping: &mut Ping,
+    rtt: Option<Duration>,
+    round_id: u64,
+) -> Option<PingResult> {
+    let (from, to) = ping.state.as_mut()?.update(rtt)?;
+    Some(PingResult::StateChanged {
+        addr,
+        binding: binding.clone(),
+        from,
+        to,
+        round_id,
+        probe: ping.probe,
+    })
 }
 
+// match a reply to the target whose probe it answers and build the results to
+// report, if any
+fn match_reply(
+    targets: &mut BTreeMap<TargetKey, Ping>,
+    received: ReceivedPing,
+    round_id: u64,
+) -> Vec<PingResult> {
+    let ReceivedPing {
+        addr,
+        binding,
+        identifier,
+        sequence_number,
+        arrived,
+        ttl,
+        size,
+        route,
+        timestamps,
+        mac,
+        names,
+        received_at,
+    } = received;
+    // Update the address to the ping response being received.  Replies from the
+    // shared sockets go to the target for the address with their identifier
+    let key = match binding {
+        Some(binding) => (addr, binding, false),
+        None => targets
+            .range((addr, Binding::default(), false)..)
+            .take_while(|&(&(target, _, _), _)| target == addr)
+            .find(|&(_, ping)| ping.get_identifier() == identifier)
+            .map_or((addr, Binding::default(), false), |(key, _)| key.clone()),
+    };
+    if let Some(ping) = targets.get_mut(&key) {
+        // the probe sent this round the reply answers, late replies to earlier
+        // attempts included.  Neighbor replies can't be told apart, the first one
+        // counts for the latest request sent before it
+        let answered = match mac {
+            _ if ping.seen => None,
+            Some(_) if ping.probe == Probe::Neighbor => ping
+                .sent
+                .iter()
+                .rev()
+                .find(|attempt| attempt.sent <= arrived)
+                .cloned(),
+            Some(_) => None,
+            None if ping.get_identifier() != identifier => None,
+            None => {
+                // port unreachables only give the low bits of the sequence number away
+                let sequence_number = match ping.probe {
+                    Probe::Udp(_) => {
+                        udp::sequence_number(ping.get_sequence_number(), sequence_number)
+                    }
+                    _ => sequence_number,
+                };
+                ping.sent
+                    .iter()
+                    .find(|attempt| attempt.sequence_number == sequence_number)
+                    .cloned()
+            }
+        };
+        if let Some(attempt) = answered {
+            ping.seen = true;
+            // timed from the attempt answered
+            let rtt = arrived.saturating_duration_since(attempt.sent);
+            events::received(addr, ping.get_identifier(), attempt.sequence_number, rtt);
+            let attempts = ping.attempts;
+            let reply = PingReply {
+                sequence_number: attempt.sequence_number,
+                identifier: ping.get_identifier(),
+                size,
+                ttl,
+                sent_at: attempt.sent_at,
+                received_at,
+                probe: ping.probe,
+            };
+            let state_changed = update_state(addr, &key.1, ping, Some(rtt), round_id);
+            let result = match (timestamps, mac, names) {
+                (_, Some(mac), _) => PingResult::Neighbor {
+                    addr,
+                    rtt,
+                    binding: key.1,
+                    mac,
+                    attempts,
+                    round_id,
+                    reply,
+                },
+                (_, _, Some(names)) => PingResult::NodeInfo {
+                    addr,
+                    rtt,
+                    binding: key.1,
+                    names,
+                    attempts,
+                    round_id,
+                    reply,
+                },
+                (Some(timestamps), None, None) => PingResult::Timestamp {
+                    addr,
+                    rtt,
+                    binding: key.1,
+                    timestamps,
+                    attempts,
+                    round_id,
+                    reply,
+                },
+                (None, None, None) => PingResult::Receive {
+                    addr,
+                    rtt,
+                    binding: key.1,
+                    ttl,
+                    size,
+                    hops: ttl.map(infer_hops),
+                    route,
+                    attempts,
+                    round_id,
+                    reply,
+                },
+            };
+            return Some(result).into_iter().chain(state_changed).collect();
+        }
+        if ping.seen {
+            events::duplicate(addr, identifier, sequence_number);
+        } else {
+            events::mismatch(
+                addr,
+                (ping.get_identifier(), ping.get_sequence_number()),
+                identifier,
+                sequence_number,
+            );
+        }
+    }
+
+    // replies to a discovery probe come from responders that aren't targets
+    let (binding, ping, attempt) = match targets
+        .iter_mut()
+        .filter(|(_, ping)| ping.discover && ping.get_identifier() == identifier)
+        .find_map(|((_, binding, _), ping)| {
+            let attempt = ping
+                .sent
+                .iter()
+                .find(|attempt| attempt.sequence_number == sequence_number)
+                .cloned()?;
+            Some((binding.clone(), ping, attempt))
+        }) {
+        Some(found) => found,
+        None => return Vec::new(),
+    };
+    if !ping.responders.insert(addr) {
+        return Vec::new();
+    }
+    ping.seen = true;
+    let rtt = arrived.saturating_duration_since(attempt.sent);
+    let reply = PingReply {
+        sequence_number,
+        identifier,
+        size,
+        ttl,
+        sent_at: attempt.sent_at,
+        received_at,
+        probe: ping.probe,
+    };
+    vec![PingResult::Discovered {
+        addr,
+        rtt,
+        binding,
+        names: names.unwrap_or_default(),
+        round_id,
+        reply,
+    }]
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn send_pings(
     size: usize,
-    timer: Arc<RwLock<Instant>>,
     stop: Arc<Mutex<bool>>,
-    results_sender: Sender<PingResult>,
+    results_sender: ResultSink,
+    thread_tx: Sender<ReceivedPing>,
     thread_rx: Arc<Mutex<Receiver<ReceivedPing>>>,
-    tx: Arc<Mutex<TransportSender>>,
-    txv6: Arc<Mutex<TransportSender>>,
-    targets: Arc<Mutex<BTreeMap<IpAddr, Ping>>>,
+    senders: Arc<Mutex<Senders>>,
+    targets: Arc<Mutex<BTreeMap<TargetKey, Ping>>>,
     max_rtt: Arc<Duration>,
+    interval: Duration,
+    retry: RetryPolicy,
+    round: Arc<Mutex<u64>>,
 ) {
+    let timeout = retry.timeout(*max_rtt, 0);
     loop {
-        for (addr, ping) in targets.lock().unwrap().iter_mut() {
-            match if addr.is_ipv4() {
-                send_echo(&mut tx.lock().unwrap(), ping, size)
-            } else if addr.is_ipv6() {
-                send_echov6(&mut txv6.lock().unwrap(), ping, size)
+        let round_id = {
+            let mut round = round.lock().unwrap();
+            *round += 1;
+            *round
+        };
+        let _round = events::round(round_id);
+        let round_start = Instant::now();
+        let started_at = SystemTime::now();
+        let keys: Vec<TargetKey> = targets.lock().unwrap().keys().cloned().collect();
+        for (i, key) in keys.iter().enumerate() {
+            // pace the probes when a rate is set, letting go of the targets meanwhile
+            if i > 0 && !interval.is_zero() {
+                thread::sleep(interval);
+            }
+            let mut targets = targets.lock().unwrap();
+            let ping = match targets.get_mut(key) {
+                Some(ping) => ping,
+                None => continue,
+            };
+            let (addr, ref binding, _) = *key;
+            ping.sent.clear();
+            if let Err(e) = send_ping(
+                &mut senders.lock().unwrap(),
+                binding,
+                ping,
+                size,
+                timeout,
+                &thread_tx,
+            ) {
+                events::send_failed(addr, &e);
             } else {
-                Ok(0)
-            } {
-                Err(e) => error!("Failed to send ping to {:?}: {}", *addr, e),
-                _ => {}
+                events::sent(addr, ping.get_identifier(), ping.get_sequence_number(), 1);
             }
             ping.seen = false;
+            ping.responders.clear();
+            ping.attempts = 1;
         }
         let start_time = Instant::now();
-        {
-            // start the timer
-            let mut timer = timer.write().unwrap();
-            *timer = start_time;
-        }
+        let mut attempt = 0;
+        let mut deadline = timeout;
         loop {
             // use recv_timeout so we don't cause a CPU to needlessly spin
             match thread_rx
                 .lock()
                 .unwrap()
-                .recv_timeout(max_rtt.saturating_sub(start_time.elapsed()))
+                .recv_timeout(deadline.saturating_sub(start_time.elapsed()))
             {
                 Ok(ping_result) => {
-                    // match ping_result {
-                    let ReceivedPing {
-                        addr,
-                        identifier,
-                        sequence_number,
-                        rtt: _,
-                    } = ping_result;
-                    // Update the address to the ping response being received
-                    if let Some(ping) = targets.lock().unwrap().get_mut(&addr) {
-                        if ping.get_identifier() == identifier
-                            && ping.get_sequence_number() == sequence_number
-                        {
-                            ping.seen = true;
-                            // Send the ping result over the client channel
-                            match results_sender.send(PingResult::Receive {
-                                addr: ping_result.addr,
-                                rtt: ping_result.rtt,
-                            }) {
-                                Ok(_) => {}
-                                Err(e) => {
-                                    if !*stop.lock().unwrap() {
-                                        error!("Error sending ping result on channel: {}", e)
-                                    }
+                    // the targets are unlocked again before the results go out
+                    let results = match_reply(&mut targets.lock().unwrap(), ping_result, round_id);
+                    for result in results {
+                        // Send the ping result over the client channel
+                        match results_sender.send(result) {
+                            Ok(_) => {}
+                            Err(e) => {
+                                if !*stop.lock().unwrap() {
+                                    events::deliver_failed(&e)
                                 }
                             }
-                        } else {
-                            debug!("Received echo reply from target {}, but sequence_number (expected {} but got {}) and identifier (expected {} but got {}) don't match", addr, ping.get_sequence_number(), sequence_number, ping.get_identifier(), identifier);
                         }
                     }
                 }
                 Err(_) => {
-                    // Check we haven't exceeded the max rtt
-                    if start_time.elapsed() >= *max_rtt {
+                    // Check we haven't exceeded the attempt's timeout
+                    if start_time.elapsed() < deadline {
+                        continue;
+                    }
+                    if attempt >= retry.retries {
+                        break;
+                    }
+                    // resend to the targets that haven't answered yet
+                    attempt += 1;
+                    let offset = start_time.elapsed();
+                    let timeout = retry.timeout(*max_rtt, attempt);
+                    let mut resent = false;
+                    for (&(addr, ref binding, _), ping) in targets.lock().unwrap().iter_mut() {
+                        if ping.seen || ping.discover {
+                            continue;
+                        }
+                        if let Err(e) = send_ping(
+                            &mut senders.lock().unwrap(),
+                            binding,
+                            ping,
+                            size,
+                            timeout,
+                            &thread_tx,
+                        ) {
+                            events::send_failed(addr, &e);
+                        } else {
+                            events::sent(
+                                addr,
+                                ping.get_identifier(),
+                                ping.get_sequence_number(),
+                                ping.attempts + 1,
+                            );
+                        }
+                        ping.attempts += 1;
+                        resent = true;
+                    }
+                    if !resent {
                         break;
                     }
+                    deadline = offset + timeout;
                 }
             }
         }
-        // check for addresses which haven't replied
-        for (addr, ping) in targets.lock().unwrap().iter() {
-            if ping.seen == false {
-                // Send the ping Idle over the client channel
-                match results_sender.send(PingResult::Idle { addr: *addr }) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        if !*stop.lock().unwrap() {
-                            error!("Error sending ping Idle result on channel: {}", e)
-                        }
+        // check for addresses which haven't replied.  Their results are delivered
+        // once the targets are unlocked, a full queue or a handler calling back into
+        // the pinger would deadlock otherwise
+        let (mut sent, mut received, mut idle) = (0, 0, 0);
+        let mut results = Vec::new();
+        for (&(addr, ref binding, _), ping) in targets.lock().unwrap().iter_mut() {
+            sent += ping.attempts as usize;
+            if ping.seen {
+                received += 1;
+            } else {
+                idle += 1;
+                events::timeout(addr, ping.attempts);
+                results.push(PingResult::Idle {
+                    addr,
+                    binding: binding.clone(),
+                    attempts: ping.attempts,
+                    round_id,
+                    probe: ping.probe,
+                });
+                results.extend(update_state(addr, binding, ping, None, round_id));
+            }
+        }
+        for result in results {
+            // Send the ping Idle over the client channel
+            match results_sender.send(result) {
+                Ok(_) => {}
+                Err(e) => {
+                    if !*stop.lock().unwrap() {
+                        events::deliver_failed(&e)
                     }
                 }
             }
         }
+        let round_complete = PingResult::RoundComplete {
+            round_id,
+            started_at,
+            duration: round_start.elapsed(),
+            sent,
+            received,
+            idle,
+        };
+        if let Err(e) = results_sender.send(round_complete) {
+            if !*stop.lock().unwrap() {
+                events::deliver_failed(&e)
+            }
+        }
         // check if we've received the stop signal
         if *stop.lock().unwrap() {
             return;
@@ -201,4 +775,66 @@ mod tests {
         p.increment_sequence_number();
         assert_eq!(p.get_sequence_number(), 1);
     }
+
+    #[test]
+    fn test_infer_hops() {
+        assert_eq!(infer_hops(64), 0);
+        assert_eq!(infer_hops(57), 7);
+        assert_eq!(infer_hops(116), 12);
+        assert_eq!(infer_hops(250), 5);
+        assert_eq!(infer_hops(30), 2);
+    }
+
+    #[test]
+    fn test_match_late_reply() {
+        let addr = "127.0.0.1".parse::<IpAddr>().unwrap();
+        let mut ping = Ping::new(addr);
+        let identifier = ping.get_identifier();
+        let start = Instant::now();
+        // two attempts, the reply to the first one comes in after the resend
+        let sent_at = SystemTime::now();
+        for sequence_number in 1..3 {
+            ping.increment_sequence_number();
+            ping.sent.push(Attempt {
+                sequence_number,
+                sent: start + Duration::from_millis(100) * (sequence_number as u32 - 1),
+                sent_at,
+            });
+        }
+        ping.attempts = 2;
+        let mut targets = BTreeMap::new();
+        targets.insert((addr, Binding::default(), false), ping);
+
+        let reply = |sequence_number| ReceivedPing {
+            addr,
+            binding: None,
+            identifier,
+            sequence_number,
+            arrived: start + Duration::from_millis(150),
+            ttl: None,
+            size: 0,
+            route: None,
+            timestamps: None,
+            mac: None,
+            names: None,
+            received_at: SystemTime::now(),
+        };
+        let results = match_reply(&mut targets, reply(1), 1);
+        match results[..] {
+            [PingResult::Receive {
+                rtt,
+                attempts,
+                ref reply,
+                ..
+            }] => {
+                assert_eq!(rtt, Duration::from_millis(150));
+                assert_eq!(attempts, 2);
+                assert_eq!(reply.sequence_number, 1);
+                assert_eq!(reply.sent_at, sent_at);
+            }
+            _ => panic!("expected the late reply to count"),
+        }
+        // the target's answered for this round
+        assert!(match_reply(&mut targets, reply(2), 1).is_empty());
+    }
 }
@@ -0,0 +1,433 @@
+// Prometheus metrics built from the results send_pings produces: per-target rtt
+// histograms, reply and loss counters, last seen times and up/down gauges, plus a
+// minimal http server for /metrics.  Series are labelled by the target's addr,
+// source, interface and probe, the source and interface empty when unset
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use sink::ResultHandler;
+use socket::Binding;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use target::Probe;
+use PingResult;
+use TargetState;
+
+// labels of a target's series, in the order target_labels gives their values
+const TARGET_LABELS: [&str; 4] = ["addr", "source", "interface", "probe"];
+// how long serve_metrics waits on a client to send its request or take the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// a prometheus Collector fed with PingResults, either through observe or as the
+// pinger's ResultHandler.  Clones share their metrics
+#[derive(Clone)]
+pub struct PingCollector {
+    rtt: HistogramVec,
+    replies: IntCounterVec,
+    lost: IntCounterVec,
+    last_seen: GaugeVec,
+    up: IntGaugeVec,
+    state: IntGaugeVec,
+}
+
+impl PingCollector {
+    pub fn new() -> Result<PingCollector, String> {
+        PingCollector::with_labels(HashMap::new())
+    }
+
+    // label every metric with these names and values on top of the target's
+    pub fn with_labels(labels: HashMap<String, String>) -> Result<PingCollector, String> {
+        let opts = |name: &str, help: &str| {
+            Opts::new(name, help)
+                .namespace("fastping")
+                .const_labels(labels.clone())
+        };
+        let buckets = exponential_buckets(0.0001, 2.0, 16).map_err(|e| e.to_string())?;
+        Ok(PingCollector {
+            rtt: HistogramVec::new(
+                HistogramOpts::from(opts("rtt_seconds", "Round trip time of replies"))
+                    .buckets(buckets),
+                &TARGET_LABELS,
+            )
+            .map_err(|e| e.to_string())?,
+            replies: IntCounterVec::new(opts("replies_total", "Replies received"), &TARGET_LABELS)
+                .map_err(|e| e.to_string())?,
+            lost: IntCounterVec::new(
+                opts("lost_total", "Rounds the target didn't answer"),
+                &TARGET_LABELS,
+            )
+            .map_err(|e| e.to_string())?,
+            last_seen: GaugeVec::new(
+                opts(
+                    "last_seen_timestamp_seconds",
+                    "Unix time of the latest reply",
+                ),
+                &TARGET_LABELS,
+            )
+            .map_err(|e| e.to_string())?,
+            up: IntGaugeVec::new(
+                opts("up", "Whether the target answered its latest round"),
+                &TARGET_LABELS,
+            )
+            .map_err(|e| e.to_string())?,
+            state: IntGaugeVec::new(
+                opts(
+                    "state",
+                    "The target's state when tracked with StateThresholds, 1 for the current one",
+                ),
+                &["addr", "source", "interface", "probe", "state"],
+            )
+            .map_err(|e| e.to_string())?,
+        })
+    }
+
+    // update the metrics with a result
+    pub fn observe(&self, result: &PingResult) {
+        match *result {
+            PingResult::Receive {
+                addr,
+                rtt,
+                ref binding,
+                ref reply,
+                ..
+            }
+            | PingResult::Timestamp {
+                addr,
+                rtt,
+                ref binding,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                rtt,
+                ref binding,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                rtt,
+                ref binding,
+                ref reply,
+                ..
+            }
+            | PingResult::Discovered {
+                addr,
+                rtt,
+                ref binding,
+                ref reply,
+                ..
+            } => {
+                let labels = target_labels(addr, binding, reply.probe);
+                self.rtt
+                    .with_label_values(&labels)
+                    .observe(rtt.as_secs_f64());
+                self.replies.with_label_values(&labels).inc();
+                let seen = reply
+                    .received_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or(Duration::ZERO);
+                self.last_seen
+                    .with_label_values(&labels)
+                    .set(seen.as_secs_f64());
+                self.up.with_label_values(&labels).set(1);
+            }
+            PingResult::Idle {
+                addr,
+                ref binding,
+                probe,
+                ..
+            } => {
+                let labels = target_labels(addr, binding, probe);
+                self.lost.with_label_values(&labels).inc();
+                self.up.with_label_values(&labels).set(0);
+            }
+            PingResult::StateChanged {
+                addr,
+                ref binding,
+                from,
+                to,
+                probe,
+                ..
+            } => {
+                let [addr, source, interface, probe] = target_labels(addr, binding, probe);
+                let mut labels = [addr, source, interface, probe, state_name(from).to_string()];
+                self.state.with_label_values(&labels).set(0);
+                labels[4] = state_name(to).to_string();
+                self.state.with_label_values(&labels).set(1);
+            }
+            PingResult::RoundComplete { .. } => {}
+        }
+    }
+
+    // drop a removed target's series, whatever their probe, so they stop being
+    // exported
+    pub fn forget(&self, addr: IpAddr, binding: &Binding) {
+        let target = target_labels(addr, binding, Probe::Echo);
+        let target = &target[..3];
+        remove_target(&self.rtt, target);
+        remove_target(&self.replies, target);
+        remove_target(&self.lost, target);
+        remove_target(&self.last_seen, target);
+        remove_target(&self.up, target);
+        remove_target(&self.state, target);
+    }
+}
+
+impl Collector for PingCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.rtt.desc();
+        descs.extend(self.replies.desc());
+        descs.extend(self.lost.desc());
+        descs.extend(self.last_seen.desc());
+        descs.extend(self.up.desc());
+        descs.extend(self.state.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.rtt.collect();
+        families.extend(self.replies.collect());
+        families.extend(self.lost.collect());
+        families.extend(self.last_seen.collect());
+        families.extend(self.up.collect());
+        families.extend(self.state.collect());
+        families
+    }
+}
+
+impl ResultHandler for PingCollector {
+    fn on_receive(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_event(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+}
+
+// values of the TARGET_LABELS of a target's series
+fn target_labels(addr: IpAddr, binding: &Binding, probe: Probe) -> [String; 4] {
+    [
+        addr.to_string(),
+        binding
+            .source
+            .map(|source| source.to_string())
+            .unwrap_or_default(),
+        binding.interface.clone().unwrap_or_default(),
+        probe.to_string(),
+    ]
+}
+
+// remove the series whose leading label values are the target's
+fn remove_target<T: MetricVecBuilder>(vec: &MetricVec<T>, target: &[String]) {
+    let names = vec.desc()[0].variable_labels.clone();
+    for family in vec.collect() {
+        for metric in family.get_metric() {
+            let values = names
+                .iter()
+                .map(|name| {
+                    metric
+                        .get_label()
+                        .iter()
+                        .find(|pair| pair.name() == name)
+                        .map_or("", |pair| pair.value())
+                })
+                .collect::<Vec<_>>();
+            if values
+                .iter()
+                .zip(target)
+                .all(|(value, target)| value == target)
+            {
+                let _ = vec.remove_label_values(&values);
+            }
+        }
+    }
+}
+
+fn state_name(state: TargetState) -> &'static str {
+    match state {
+        TargetState::Unknown => "unknown",
+        TargetState::Up => "up",
+        TargetState::Down => "down",
+        TargetState::Degraded => "degraded",
+    }
+}
+
+// serve the registry's metrics in the text format on GET /metrics from a thread of
+// its own, returning the address listened on.  Connections are answered one at a
+// time, and one that stalls is dropped after CLIENT_TIMEOUT so the next scrape isn't
+// held up for long
+pub fn serve_metrics(addr: SocketAddr, registry: Registry) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream, &registry) {
+                        debug!("Error serving metrics: {}", e);
+                    }
+                }
+                Err(e) => error!("Error accepting metrics connection: {}", e),
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+fn respond(mut stream: TcpStream, registry: &Registry) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let encoder = TextEncoder::new();
+            let mut body = Vec::new();
+            encoder
+                .encode(&registry.gather(), &mut body)
+                .map_err(|e| io::Error::other(e.to_string()))?;
+            ("200 OK", encoder.format_type().to_string(), body)
+        }
+        _ => (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"not found\n".to_vec(),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ping::PingReply;
+    use socket::Binding;
+    use std::io::Read;
+    use target::Probe;
+
+    #[test]
+    fn test_collector() {
+        let mut labels = HashMap::new();
+        labels.insert("site".to_string(), "lab".to_string());
+        let collector = PingCollector::with_labels(labels).unwrap();
+        let registry = Registry::new();
+        registry.register(Box::new(collector.clone())).unwrap();
+
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let binding = Binding {
+            source: Some("192.0.2.100".parse().unwrap()),
+            interface: Some("eth1".to_string()),
+        };
+        let received_at = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        collector.observe(&PingResult::Receive {
+            addr,
+            rtt: Duration::from_millis(3),
+            binding: binding.clone(),
+            ttl: Some(64),
+            size: 24,
+            hops: Some(0),
+            route: None,
+            attempts: 1,
+            round_id: 1,
+            reply: PingReply {
+                sequence_number: 1,
+                identifier: 7,
+                size: 24,
+                ttl: Some(64),
+                sent_at: received_at - Duration::from_millis(3),
+                received_at,
+                probe: Probe::Echo,
+            },
+        });
+        collector.observe(&PingResult::Idle {
+            addr,
+            binding: binding.clone(),
+            attempts: 1,
+            round_id: 2,
+            probe: Probe::Echo,
+        });
+        collector.observe(&PingResult::StateChanged {
+            addr,
+            binding: binding.clone(),
+            from: TargetState::Unknown,
+            to: TargetState::Down,
+            round_id: 2,
+            probe: Probe::Echo,
+        });
+        // a target reached through the default binding keeps its series
+        collector.observe(&PingResult::Idle {
+            addr,
+            binding: Binding::default(),
+            attempts: 1,
+            round_id: 2,
+            probe: Probe::Tcp(443),
+        });
+
+        let listening = serve_metrics("127.0.0.1:0".parse().unwrap(), registry).unwrap();
+        // a client that never sends its request only holds up the next one until it
+        // times out
+        let _stalled = TcpStream::connect(listening).unwrap();
+        let mut stream = TcpStream::connect(listening).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        for line in [
+            "fastping_replies_total{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\"} 1",
+            "fastping_lost_total{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\"} 1",
+            "fastping_up{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\"} 0",
+            "fastping_last_seen_timestamp_seconds{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\"} 1000000",
+            "fastping_rtt_seconds_count{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\"} 1",
+            "fastping_state{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\",state=\"down\"} 1",
+            "fastping_state{addr=\"192.0.2.1\",interface=\"eth1\",probe=\"echo\",site=\"lab\",source=\"192.0.2.100\",state=\"unknown\"} 0",
+        ]
+        .iter()
+        {
+            assert!(response.contains(line), "missing {}", line);
+        }
+
+        collector.forget(addr, &binding);
+        let remaining = collector
+            .collect()
+            .iter()
+            .map(|family| family.get_metric().len())
+            .sum::<usize>();
+        // the default binding's lost counter and up gauge
+        assert_eq!(remaining, 2);
+        collector.forget(addr, &Binding::default());
+        assert!(collector
+            .collect()
+            .iter()
+            .all(|family| family.get_metric().is_empty()));
+    }
+}
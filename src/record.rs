@@ -0,0 +1,1070 @@
+// Recording results to an append-only file and replaying them.  A recording is the
+// magic bytes FPRC and a format version, a major and a minor byte, then one record
+// per PingResult: a big-endian u32 length and the result's encoding, a kind byte
+// followed by its fields.  Readers skip kinds they don't know and read any minor
+// version of their major one, so later minor versions can add kinds without
+// breaking older readers; anything else takes a new major version.  Times are
+// nanoseconds since the unix epoch and durations nanoseconds
+use ping::PingReply;
+use pnet::util::MacAddr;
+use route::RecordedRoute;
+use sink::{self, ResultHandler};
+use socket::Binding;
+use state::{StateThresholds, StateTracker};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use target::Probe;
+use timestamp::TimestampReply;
+use PingResult;
+use TargetState;
+
+const MAGIC: &[u8; 4] = b"FPRC";
+// the format version written, the major version in the high byte and the minor in
+// the low one.  Recordings of the same major version are read and appended to
+pub const RECORDING_VERSION: u16 = 0x0100;
+const HEADER_LENGTH: u64 = 6;
+// longest record read or written, well above what any result encodes to, so a
+// corrupt length isn't taken for a huge allocation
+const MAX_RECORD_LENGTH: usize = 1 << 20;
+
+const IDLE: u8 = 1;
+const RECEIVE: u8 = 2;
+const TIMESTAMP: u8 = 3;
+const NEIGHBOR: u8 = 4;
+const NODE_INFO: u8 = 5;
+const DISCOVERED: u8 = 6;
+const STATE_CHANGED: u8 = 7;
+const ROUND_COMPLETE: u8 = 8;
+
+// writes results to a recording, either through record or as the pinger's
+// ResultHandler
+pub struct Recorder<W: Write> {
+    writer: W,
+}
+
+impl Recorder<BufWriter<File>> {
+    // append to the recording at path, creating it if it doesn't exist.  An existing
+    // recording has to be of the major version written, and a record left partly
+    // written, say by a crash, is cut off before appending
+    pub fn append<P: AsRef<Path>>(path: P) -> Result<Recorder<BufWriter<File>>, String> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        let length = file.metadata().map_err(|e| e.to_string())?.len();
+        if length != 0 {
+            read_header(&mut file).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let complete = complete_length(&mut file).map_err(|e| e.to_string())?;
+            if complete < length {
+                warn!(
+                    "{}: dropping {} bytes of a partly written record",
+                    path.display(),
+                    length - complete
+                );
+                file.set_len(complete).map_err(|e| e.to_string())?;
+            }
+            return Ok(Recorder {
+                writer: BufWriter::new(file),
+            });
+        }
+        Recorder::new(BufWriter::new(file)).map_err(|e| e.to_string())
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    // start a new recording, writing its header
+    pub fn new(mut writer: W) -> io::Result<Recorder<W>> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&RECORDING_VERSION.to_be_bytes())?;
+        Ok(Recorder { writer })
+    }
+
+    pub fn record(&mut self, result: &PingResult) -> io::Result<()> {
+        let record = encode(result);
+        if record.len() > MAX_RECORD_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("a {} byte record is too long", record.len()),
+            ));
+        }
+        self.writer
+            .write_all(&(record.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> ResultHandler for Recorder<W> {
+    fn on_receive(&mut self, result: PingResult) {
+        record_or_log(self.record(&result));
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        record_or_log(self.record(&result));
+    }
+
+    fn on_event(&mut self, result: PingResult) {
+        record_or_log(self.record(&result));
+    }
+
+    // flushed once the round is recorded, so a crash loses at most the round underway
+    fn on_round_complete(&mut self, result: PingResult) {
+        record_or_log(self.record(&result).and_then(|_| self.flush()));
+    }
+}
+
+fn record_or_log(recorded: io::Result<()>) {
+    if let Err(e) = recorded {
+        error!("Error recording ping result: {}", e);
+    }
+}
+
+// the results of a recording, in the order they were recorded.  With states set,
+// recorded StateChanged results are dropped and new ones worked out from the
+// replies and Idle results with the given thresholds, the way the pinger does, so
+// a recording can be replayed against thresholds other than the ones it ran with
+pub struct Replay<R: Read> {
+    reader: R,
+    version: u16,
+    states: Option<StateThresholds>,
+    trackers: BTreeMap<(IpAddr, Binding), StateTracker>,
+    // a StateChanged waiting to follow the result that caused it
+    pending: Option<PingResult>,
+    done: bool,
+}
+
+impl Replay<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Replay<BufReader<File>>, String> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Replay::new(BufReader::new(file)).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+}
+
+impl<R: Read> Replay<R> {
+    // read a recording's header, failing for major versions other than this one's
+    pub fn new(mut reader: R) -> Result<Replay<R>, String> {
+        let version = read_header(&mut reader)?;
+        Ok(Replay {
+            reader,
+            version,
+            states: None,
+            trackers: BTreeMap::new(),
+            pending: None,
+            done: false,
+        })
+    }
+
+    pub fn states(mut self, thresholds: StateThresholds) -> Replay<R> {
+        self.states = Some(thresholds);
+        self
+    }
+
+    // the format version of the recording
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    // deliver every result to the handler's callbacks as the pinger would
+    pub fn run<H: ResultHandler>(self, handler: &mut H) -> Result<(), String> {
+        for result in self {
+            sink::deliver(handler, result?);
+        }
+        Ok(())
+    }
+
+    // the next record, None at the end of the recording
+    fn read_record(&mut self) -> Result<Option<PingResult>, String> {
+        loop {
+            let mut length = [0; 4];
+            match read_full(&mut self.reader, &mut length) {
+                Ok(0) => return Ok(None),
+                Ok(4) => {}
+                Ok(_) => return Err("recording ends partway through a record".to_string()),
+                Err(e) => return Err(e.to_string()),
+            }
+            let length = u32::from_be_bytes(length) as usize;
+            if length > MAX_RECORD_LENGTH {
+                return Err(format!("a {} byte record is too long", length));
+            }
+            let mut record = vec![0; length];
+            match read_full(&mut self.reader, &mut record) {
+                Ok(n) if n == record.len() => {}
+                Ok(_) => return Err("recording ends partway through a record".to_string()),
+                Err(e) => return Err(e.to_string()),
+            }
+            if let Some(result) = decode(&record)? {
+                return Ok(Some(result));
+            }
+        }
+    }
+
+    // run the state machine over a result, returning the StateChanged it causes
+    fn track(&mut self, result: &PingResult) -> Option<PingResult> {
+        let thresholds = self.states?;
+        let (addr, binding, rtt, round_id, probe) = match *result {
+            PingResult::Receive {
+                addr,
+                ref binding,
+                rtt,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::Timestamp {
+                addr,
+                ref binding,
+                rtt,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                ref binding,
+                rtt,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                ref binding,
+                rtt,
+                round_id,
+                ref reply,
+                ..
+            } => (addr, binding, Some(rtt), round_id, reply.probe),
+            PingResult::Idle {
+                addr,
+                ref binding,
+                round_id,
+                probe,
+                ..
+            } => (addr, binding, None, round_id, probe),
+            _ => return None,
+        };
+        let (from, to) = self
+            .trackers
+            .entry((addr, binding.clone()))
+            .or_insert_with(|| StateTracker::new(thresholds))
+            .update(rtt)?;
+        Some(PingResult::StateChanged {
+            addr,
+            binding: binding.clone(),
+            from,
+            to,
+            round_id,
+            probe,
+        })
+    }
+}
+
+impl<R: Read> Iterator for Replay<R> {
+    type Item = Result<PingResult, String>;
+
+    fn next(&mut self) -> Option<Result<PingResult, String>> {
+        if let Some(result) = self.pending.take() {
+            return Some(Ok(result));
+        }
+        while !self.done {
+            match self.read_record() {
+                Ok(Some(PingResult::StateChanged { .. })) if self.states.is_some() => continue,
+                Ok(Some(result)) => {
+                    self.pending = self.track(&result);
+                    return Some(Ok(result));
+                }
+                Ok(None) => self.done = true,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+fn read_header<R: Read>(reader: &mut R) -> Result<u16, String> {
+    let mut header = [0; 6];
+    match read_full(reader, &mut header) {
+        Ok(6) if &header[..4] == MAGIC => {}
+        Ok(_) => return Err("not a fastping recording".to_string()),
+        Err(e) => return Err(e.to_string()),
+    }
+    if header[4] != RECORDING_VERSION.to_be_bytes()[0] {
+        return Err(format!(
+            "unsupported recording version {}.{}",
+            header[4], header[5]
+        ));
+    }
+    Ok(u16::from_be_bytes([header[4], header[5]]))
+}
+
+// the length of a recording up to the end of its last complete record, reading on
+// from just after the header
+fn complete_length(file: &mut File) -> io::Result<u64> {
+    let mut reader = BufReader::new(file);
+    let mut complete = HEADER_LENGTH;
+    loop {
+        let mut length = [0; 4];
+        if read_full(&mut reader, &mut length)? < 4 {
+            break;
+        }
+        let length = u32::from_be_bytes(length) as u64;
+        if length > MAX_RECORD_LENGTH as u64
+            || io::copy(&mut reader.by_ref().take(length), &mut io::sink())? < length
+        {
+            break;
+        }
+        complete += 4 + length;
+    }
+    reader.into_inner().seek(SeekFrom::Start(complete))?;
+    Ok(complete)
+}
+
+// fill buf as far as the reader goes, returning the bytes read
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn encode(result: &PingResult) -> Vec<u8> {
+    let mut out = Encoder(Vec::new());
+    match *result {
+        PingResult::Idle {
+            addr,
+            ref binding,
+            attempts,
+            round_id,
+            probe,
+        } => {
+            out.u8(IDLE);
+            out.addr(addr);
+            out.binding(binding);
+            out.u32(attempts);
+            out.u64(round_id);
+            out.probe(probe);
+        }
+        PingResult::Receive {
+            addr,
+            rtt,
+            ref binding,
+            ttl,
+            size,
+            hops,
+            ref route,
+            attempts,
+            round_id,
+            ref reply,
+        } => {
+            out.u8(RECEIVE);
+            out.addr(addr);
+            out.duration(rtt);
+            out.binding(binding);
+            out.optional_u8(ttl);
+            out.u32(size as u32);
+            out.optional_u8(hops);
+            match *route {
+                Some(ref route) => {
+                    out.u8(1);
+                    out.u8(route.addresses.len() as u8);
+                    for address in &route.addresses {
+                        out.0.extend_from_slice(&address.octets());
+                    }
+                    out.u8(route.timestamps.len() as u8);
+                    for &timestamp in &route.timestamps {
+                        out.u32(timestamp);
+                    }
+                    out.u8(route.overflow);
+                }
+                None => out.u8(0),
+            }
+            out.u32(attempts);
+            out.u64(round_id);
+            out.reply(reply);
+        }
+        PingResult::Timestamp {
+            addr,
+            rtt,
+            ref binding,
+            timestamps,
+            attempts,
+            round_id,
+            ref reply,
+        } => {
+            out.u8(TIMESTAMP);
+            out.addr(addr);
+            out.duration(rtt);
+            out.binding(binding);
+            out.u32(timestamps.originate);
+            out.u32(timestamps.receive);
+            out.u32(timestamps.transmit);
+            out.u32(timestamps.arrival);
+            out.u32(attempts);
+            out.u64(round_id);
+            out.reply(reply);
+        }
+        PingResult::Neighbor {
+            addr,
+            rtt,
+            ref binding,
+            mac,
+            attempts,
+            round_id,
+            ref reply,
+        } => {
+            out.u8(NEIGHBOR);
+            out.addr(addr);
+            out.duration(rtt);
+            out.binding(binding);
+            out.0
+                .extend_from_slice(&[mac.0, mac.1, mac.2, mac.3, mac.4, mac.5]);
+            out.u32(attempts);
+            out.u64(round_id);
+            out.reply(reply);
+        }
+        PingResult::NodeInfo {
+            addr,
+            rtt,
+            ref binding,
+            ref names,
+            attempts,
+            round_id,
+            ref reply,
+        } => {
+            out.u8(NODE_INFO);
+            out.addr(addr);
+            out.duration(rtt);
+            out.binding(binding);
+            out.names(names);
+            out.u32(attempts);
+            out.u64(round_id);
+            out.reply(reply);
+        }
+        PingResult::Discovered {
+            addr,
+            rtt,
+            ref binding,
+            ref names,
+            round_id,
+            ref reply,
+        } => {
+            out.u8(DISCOVERED);
+            out.addr(addr);
+            out.duration(rtt);
+            out.binding(binding);
+            out.names(names);
+            out.u64(round_id);
+            out.reply(reply);
+        }
+        PingResult::StateChanged {
+            addr,
+            ref binding,
+            from,
+            to,
+            round_id,
+            probe,
+        } => {
+            out.u8(STATE_CHANGED);
+            out.addr(addr);
+            out.binding(binding);
+            out.u8(state_code(from));
+            out.u8(state_code(to));
+            out.u64(round_id);
+            out.probe(probe);
+        }
+        PingResult::RoundComplete {
+            round_id,
+            started_at,
+            duration,
+            sent,
+            received,
+            idle,
+        } => {
+            out.u8(ROUND_COMPLETE);
+            out.u64(round_id);
+            out.time(started_at);
+            out.duration(duration);
+            out.u64(sent as u64);
+            out.u64(received as u64);
+            out.u64(idle as u64);
+        }
+    }
+    out.0
+}
+
+// decode a record, None for a kind this version doesn't know
+fn decode(record: &[u8]) -> Result<Option<PingResult>, String> {
+    let mut input = Decoder(record);
+    let result = match input.u8()? {
+        IDLE => PingResult::Idle {
+            addr: input.addr()?,
+            binding: input.binding()?,
+            attempts: input.u32()?,
+            round_id: input.u64()?,
+            probe: input.probe()?,
+        },
+        RECEIVE => PingResult::Receive {
+            addr: input.addr()?,
+            rtt: input.duration()?,
+            binding: input.binding()?,
+            ttl: input.optional_u8()?,
+            size: input.u32()? as usize,
+            hops: input.optional_u8()?,
+            route: match input.u8()? {
+                0 => None,
+                _ => {
+                    let mut route = RecordedRoute::default();
+                    for _ in 0..input.u8()? {
+                        let octets = input.bytes(4)?;
+                        route
+                            .addresses
+                            .push(Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]));
+                    }
+                    for _ in 0..input.u8()? {
+                        route.timestamps.push(input.u32()?);
+                    }
+                    route.overflow = input.u8()?;
+                    Some(route)
+                }
+            },
+            attempts: input.u32()?,
+            round_id: input.u64()?,
+            reply: input.reply()?,
+        },
+        TIMESTAMP => PingResult::Timestamp {
+            addr: input.addr()?,
+            rtt: input.duration()?,
+            binding: input.binding()?,
+            timestamps: TimestampReply {
+                originate: input.u32()?,
+                receive: input.u32()?,
+                transmit: input.u32()?,
+                arrival: input.u32()?,
+            },
+            attempts: input.u32()?,
+            round_id: input.u64()?,
+            reply: input.reply()?,
+        },
+        NEIGHBOR => PingResult::Neighbor {
+            addr: input.addr()?,
+            rtt: input.duration()?,
+            binding: input.binding()?,
+            mac: {
+                let m = input.bytes(6)?;
+                MacAddr::new(m[0], m[1], m[2], m[3], m[4], m[5])
+            },
+            attempts: input.u32()?,
+            round_id: input.u64()?,
+            reply: input.reply()?,
+        },
+        NODE_INFO => PingResult::NodeInfo {
+            addr: input.addr()?,
+            rtt: input.duration()?,
+            binding: input.binding()?,
+            names: input.names()?,
+            attempts: input.u32()?,
+            round_id: input.u64()?,
+            reply: input.reply()?,
+        },
+        DISCOVERED => PingResult::Discovered {
+            addr: input.addr()?,
+            rtt: input.duration()?,
+            binding: input.binding()?,
+            names: input.names()?,
+            round_id: input.u64()?,
+            reply: input.reply()?,
+        },
+        STATE_CHANGED => PingResult::StateChanged {
+            addr: input.addr()?,
+            binding: input.binding()?,
+            from: input.state()?,
+            to: input.state()?,
+            round_id: input.u64()?,
+            probe: input.probe()?,
+        },
+        ROUND_COMPLETE => PingResult::RoundComplete {
+            round_id: input.u64()?,
+            started_at: input.time()?,
+            duration: input.duration()?,
+            sent: input.u64()? as usize,
+            received: input.u64()? as usize,
+            idle: input.u64()? as usize,
+        },
+        _ => return Ok(None),
+    };
+    Ok(Some(result))
+}
+
+fn state_code(state: TargetState) -> u8 {
+    match state {
+        TargetState::Unknown => 0,
+        TargetState::Up => 1,
+        TargetState::Down => 2,
+        TargetState::Degraded => 3,
+    }
+}
+
+struct Encoder(Vec<u8>);
+
+impl Encoder {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_be_bytes());
+    }
+
+    // a presence byte, then the value
+    fn optional_u8(&mut self, value: Option<u8>) {
+        match value {
+            Some(value) => {
+                self.u8(1);
+                self.u8(value);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn duration(&mut self, duration: Duration) {
+        self.u64(duration.as_nanos() as u64);
+    }
+
+    fn time(&mut self, time: SystemTime) {
+        self.duration(time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO));
+    }
+
+    // the ip version, then the address
+    fn addr(&mut self, addr: IpAddr) {
+        match addr {
+            IpAddr::V4(addr) => {
+                self.u8(4);
+                self.0.extend_from_slice(&addr.octets());
+            }
+            IpAddr::V6(addr) => {
+                self.u8(6);
+                self.0.extend_from_slice(&addr.octets());
+            }
+        }
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+
+    fn names(&mut self, names: &[String]) {
+        self.u16(names.len() as u16);
+        for name in names {
+            self.string(name);
+        }
+    }
+
+    // the source address and interface, each after a presence byte
+    fn binding(&mut self, binding: &Binding) {
+        match binding.source {
+            Some(source) => {
+                self.u8(1);
+                self.addr(source);
+            }
+            None => self.u8(0),
+        }
+        match binding.interface {
+            Some(ref interface) => {
+                self.u8(1);
+                self.string(interface);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    // the kind of probe, then its port or 0
+    fn probe(&mut self, probe: Probe) {
+        let (kind, port) = match probe {
+            Probe::Echo => (0, 0),
+            Probe::Timestamp => (1, 0),
+            Probe::Tcp(port) => (2, port),
+            Probe::Udp(port) => (3, port),
+            Probe::Neighbor => (4, 0),
+            Probe::NodeInfo => (5, 0),
+        };
+        self.u8(kind);
+        self.u16(port);
+    }
+
+    fn reply(&mut self, reply: &PingReply) {
+        self.u16(reply.sequence_number);
+        self.u16(reply.identifier);
+        self.u32(reply.size as u32);
+        self.optional_u8(reply.ttl);
+        self.time(reply.sent_at);
+        self.time(reply.received_at);
+        self.probe(reply.probe);
+    }
+}
+
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err("truncated record".to_string());
+        }
+        let (bytes, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn optional_u8(&mut self) -> Result<Option<u8>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u8()?)),
+        }
+    }
+
+    fn duration(&mut self) -> Result<Duration, String> {
+        Ok(Duration::from_nanos(self.u64()?))
+    }
+
+    fn time(&mut self) -> Result<SystemTime, String> {
+        Ok(UNIX_EPOCH + self.duration()?)
+    }
+
+    fn addr(&mut self) -> Result<IpAddr, String> {
+        match self.u8()? {
+            4 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(self.bytes(4)?);
+                Ok(IpAddr::V4(Ipv4Addr::from(octets)))
+            }
+            6 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(self.bytes(16)?);
+                Ok(IpAddr::V6(Ipv6Addr::from(octets)))
+            }
+            version => Err(format!("bad address version {}", version)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|e| e.to_string())
+    }
+
+    fn names(&mut self) -> Result<Vec<String>, String> {
+        (0..self.u16()?).map(|_| self.string()).collect()
+    }
+
+    fn binding(&mut self) -> Result<Binding, String> {
+        let source = match self.u8()? {
+            0 => None,
+            _ => Some(self.addr()?),
+        };
+        let interface = match self.u8()? {
+            0 => None,
+            _ => Some(self.string()?),
+        };
+        Ok(Binding { source, interface })
+    }
+
+    fn probe(&mut self) -> Result<Probe, String> {
+        let kind = self.u8()?;
+        let port = self.u16()?;
+        match kind {
+            0 => Ok(Probe::Echo),
+            1 => Ok(Probe::Timestamp),
+            2 => Ok(Probe::Tcp(port)),
+            3 => Ok(Probe::Udp(port)),
+            4 => Ok(Probe::Neighbor),
+            5 => Ok(Probe::NodeInfo),
+            kind => Err(format!("bad probe kind {}", kind)),
+        }
+    }
+
+    fn state(&mut self) -> Result<TargetState, String> {
+        match self.u8()? {
+            0 => Ok(TargetState::Unknown),
+            1 => Ok(TargetState::Up),
+            2 => Ok(TargetState::Down),
+            3 => Ok(TargetState::Degraded),
+            state => Err(format!("bad target state {}", state)),
+        }
+    }
+
+    fn reply(&mut self) -> Result<PingReply, String> {
+        Ok(PingReply {
+            sequence_number: self.u16()?,
+            identifier: self.u16()?,
+            size: self.u32()? as usize,
+            ttl: self.optional_u8()?,
+            sent_at: self.time()?,
+            received_at: self.time()?,
+            probe: self.probe()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stats::Statistics;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn reply(at: SystemTime, probe: Probe) -> PingReply {
+        PingReply {
+            sequence_number: 3,
+            identifier: 7,
+            size: 64,
+            ttl: Some(64),
+            sent_at: at,
+            received_at: at + Duration::from_micros(1500),
+            probe,
+        }
+    }
+
+    fn results() -> Vec<PingResult> {
+        let at = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let binding = Binding {
+            source: Some("192.0.2.100".parse().unwrap()),
+            interface: Some("eth0".to_string()),
+        };
+        vec![
+            PingResult::Receive {
+                addr,
+                rtt: Duration::from_micros(1500),
+                binding: binding.clone(),
+                ttl: Some(64),
+                size: 64,
+                hops: Some(0),
+                route: Some(RecordedRoute {
+                    addresses: vec![Ipv4Addr::new(192, 0, 2, 254)],
+                    timestamps: vec![1000],
+                    overflow: 1,
+                }),
+                attempts: 1,
+                round_id: 1,
+                reply: reply(at, Probe::Echo),
+            },
+            PingResult::Timestamp {
+                addr,
+                rtt: Duration::from_millis(2),
+                binding: Binding::default(),
+                timestamps: TimestampReply {
+                    originate: 1,
+                    receive: 2,
+                    transmit: 3,
+                    arrival: 4,
+                },
+                attempts: 2,
+                round_id: 1,
+                reply: reply(at, Probe::Timestamp),
+            },
+            PingResult::Neighbor {
+                addr,
+                rtt: Duration::from_millis(1),
+                binding: Binding::default(),
+                mac: MacAddr::new(2, 0, 0, 0, 0, 1),
+                attempts: 1,
+                round_id: 1,
+                reply: reply(at, Probe::Neighbor),
+            },
+            PingResult::NodeInfo {
+                addr: "2001:db8::1".parse().unwrap(),
+                rtt: Duration::from_millis(1),
+                binding: Binding::default(),
+                names: vec!["host.example".to_string()],
+                attempts: 1,
+                round_id: 1,
+                reply: reply(at, Probe::NodeInfo),
+            },
+            PingResult::Discovered {
+                addr: "2001:db8::2".parse().unwrap(),
+                rtt: Duration::from_millis(1),
+                binding: Binding::default(),
+                names: Vec::new(),
+                round_id: 1,
+                reply: reply(at, Probe::Echo),
+            },
+            PingResult::Idle {
+                addr: "192.0.2.2".parse().unwrap(),
+                binding,
+                attempts: 3,
+                round_id: 1,
+                probe: Probe::Udp(33434),
+            },
+            PingResult::StateChanged {
+                addr,
+                binding: Binding::default(),
+                from: TargetState::Unknown,
+                to: TargetState::Up,
+                round_id: 1,
+                probe: Probe::Echo,
+            },
+            PingResult::RoundComplete {
+                round_id: 1,
+                started_at: at,
+                duration: Duration::from_secs(1),
+                sent: 9,
+                received: 5,
+                idle: 1,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        for result in results() {
+            recorder.on_receive(result);
+        }
+        // a kind from a later version is skipped
+        let mut recording = recorder.into_inner();
+        recording.extend_from_slice(&[0, 0, 0, 2, 99, 0]);
+
+        let replay = Replay::new(&recording[..]).unwrap();
+        assert_eq!(replay.version(), RECORDING_VERSION);
+        let replayed: Vec<PingResult> = replay.map(Result::unwrap).collect();
+        assert_eq!(replayed.len(), results().len());
+        for (replayed, recorded) in replayed.iter().zip(results().iter()) {
+            assert_eq!(encode(replayed), encode(recorded));
+        }
+
+        // a partly written last record is reported once
+        let mut replay = Replay::new(&recording[..recording.len() - 3]).unwrap();
+        assert!(replay.by_ref().take(8).all(|result| result.is_ok()));
+        assert!(replay.next().unwrap().is_err());
+        assert!(replay.next().is_none());
+
+        // later minor versions are read, other major versions aren't
+        assert_eq!(Replay::new(&b"FPRC\x01\x07"[..]).unwrap().version(), 0x0107);
+        assert!(Replay::new(&b"FPRC\x02\x00"[..]).is_err());
+        assert!(Replay::new(&b"FPRC\x00\x01"[..]).is_err());
+        assert!(Replay::new(&b"PCAP\x01\x00"[..]).is_err());
+
+        // a length past the cap is an error rather than an allocation
+        let mut huge = b"FPRC\x01\x00".to_vec();
+        huge.extend_from_slice(&u32::MAX.to_be_bytes());
+        let mut replay = Replay::new(&huge[..]).unwrap();
+        assert!(replay.next().unwrap().is_err());
+        assert!(replay.next().is_none());
+    }
+
+    #[test]
+    fn test_replay_states() {
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        // the binding of the first of results()
+        let binding = Binding {
+            source: Some("192.0.2.100".parse().unwrap()),
+            interface: Some("eth0".to_string()),
+        };
+        let idle = |round_id| PingResult::Idle {
+            addr,
+            binding: binding.clone(),
+            attempts: 1,
+            round_id,
+            probe: Probe::Echo,
+        };
+        let path = env::temp_dir().join(format!("fastping-replay-{}.rec", process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let mut recorder = Recorder::append(&path).unwrap();
+            recorder.record(&results()[0]).unwrap();
+            recorder.flush().unwrap();
+        }
+        {
+            // a record cut short, say by a crash, is dropped before appending
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&[0, 0, 0, 40, IDLE]).unwrap();
+        }
+        {
+            // appending picks up after the existing records
+            let mut recorder = Recorder::append(&path).unwrap();
+            recorder.record(&idle(2)).unwrap();
+            recorder.record(&idle(3)).unwrap();
+            recorder.flush().unwrap();
+        }
+
+        let states = |thresholds| {
+            Replay::open(&path)
+                .unwrap()
+                .states(thresholds)
+                .map(Result::unwrap)
+                .filter_map(|result| match result {
+                    PingResult::StateChanged { to, round_id, .. } => Some((to, round_id)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let thresholds = StateThresholds::default().up_after(1).down_after(1);
+        assert_eq!(
+            states(thresholds),
+            vec![(TargetState::Up, 1), (TargetState::Down, 2)]
+        );
+        let thresholds = StateThresholds::default().up_after(1).down_after(2);
+        assert_eq!(
+            states(thresholds),
+            vec![(TargetState::Up, 1), (TargetState::Down, 3)]
+        );
+
+        let mut stats = Statistics::new();
+        Replay::open(&path).unwrap().run(&mut stats).unwrap();
+        let target = stats.get(addr, &binding).unwrap();
+        assert_eq!((target.rounds, target.received, target.lost), (3, 1, 2));
+        fs::remove_file(&path).unwrap();
+    }
+}
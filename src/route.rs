@@ -0,0 +1,186 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::net::Ipv4Addr;
+
+// ipv4 option numbers for Record Route and Internet Timestamp (RFC 791)
+const RECORD_ROUTE: u8 = 7;
+const TIMESTAMP: u8 = 68;
+
+// most hops either option has room for
+const MAX_HOPS: usize = 9;
+
+// which ipv4 option to send with echo requests to have the hops on the forward and
+// return paths fill in their details.  Timestamps are in milliseconds since midnight UT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordMode {
+    // Record Route, up to nine addresses
+    Route,
+    // Internet Timestamp with timestamps only, up to nine
+    Timestamp,
+    // Internet Timestamp with address and timestamp pairs, up to four
+    TimestampAndAddress,
+}
+
+impl RecordMode {
+    // the empty option to send, padded to a multiple of 4 bytes
+    pub(crate) fn option_bytes(self) -> Vec<u8> {
+        let mut option = match self {
+            RecordMode::Route => {
+                let mut option = vec![0; 3 + MAX_HOPS * 4];
+                option[0] = RECORD_ROUTE;
+                option[2] = 4;
+                option
+            }
+            RecordMode::Timestamp => {
+                let mut option = vec![0; 4 + MAX_HOPS * 4];
+                option[0] = TIMESTAMP;
+                option[2] = 5;
+                option
+            }
+            RecordMode::TimestampAndAddress => {
+                let mut option = vec![0; 4 + 4 * 8];
+                option[0] = TIMESTAMP;
+                option[2] = 5;
+                option[3] = 1;
+                option
+            }
+        };
+        option[1] = option.len() as u8;
+        while option.len() % 4 != 0 {
+            option.push(0);
+        }
+        option
+    }
+}
+
+// the filled in Record Route or Timestamp option of a reply.  For Timestamp with
+// addresses, addresses[i] recorded timestamps[i]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RecordedRoute {
+    pub addresses: Vec<Ipv4Addr>,
+    pub timestamps: Vec<u32>,
+    // number of hops that couldn't record a timestamp because the option was full
+    pub overflow: u8,
+}
+
+// find and parse a Record Route or Timestamp option in a reply's ipv4 options
+pub fn parse(options: &[u8]) -> Option<RecordedRoute> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            // end of option list
+            0 => return None,
+            // no operation
+            1 => i += 1,
+            kind => {
+                if i + 1 >= options.len() {
+                    return None;
+                }
+                let len = options[i + 1] as usize;
+                if len < 4 || i + len > options.len() {
+                    return None;
+                }
+                let option = &options[i..i + len];
+                match kind {
+                    RECORD_ROUTE => return Some(parse_record_route(option)),
+                    TIMESTAMP => return Some(parse_timestamp(option)),
+                    _ => i += len,
+                }
+            }
+        }
+    }
+    None
+}
+
+// the bytes filled in so far, from the first slot up to the pointer
+fn recorded(option: &[u8], first_slot: usize) -> &[u8] {
+    let end = (option[2] as usize).saturating_sub(1).min(option.len());
+    if end <= first_slot {
+        return &[];
+    }
+    &option[first_slot..end]
+}
+
+fn parse_record_route(option: &[u8]) -> RecordedRoute {
+    RecordedRoute {
+        addresses: recorded(option, 3)
+            .chunks_exact(4)
+            .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+            .collect(),
+        ..RecordedRoute::default()
+    }
+}
+
+fn parse_timestamp(option: &[u8]) -> RecordedRoute {
+    let mut route = RecordedRoute {
+        overflow: option[3] >> 4,
+        ..RecordedRoute::default()
+    };
+    let data = recorded(option, 4);
+    if option[3] & 0x0f == 0 {
+        route.timestamps = data
+            .chunks_exact(4)
+            .map(|t| u32::from_be_bytes([t[0], t[1], t[2], t[3]]))
+            .collect();
+    } else {
+        for pair in data.chunks_exact(8) {
+            route
+                .addresses
+                .push(Ipv4Addr::new(pair[0], pair[1], pair[2], pair[3]));
+            route
+                .timestamps
+                .push(u32::from_be_bytes([pair[4], pair[5], pair[6], pair[7]]));
+        }
+    }
+    route
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_option_bytes() {
+        let option = RecordMode::Route.option_bytes();
+        assert_eq!(option.len(), 40);
+        assert_eq!(&option[..3], &[7, 39, 4]);
+
+        let option = RecordMode::Timestamp.option_bytes();
+        assert_eq!(option.len(), 40);
+        assert_eq!(&option[..4], &[68, 40, 5, 0]);
+
+        let option = RecordMode::TimestampAndAddress.option_bytes();
+        assert_eq!(option.len(), 36);
+        assert_eq!(&option[..4], &[68, 36, 5, 1]);
+    }
+
+    #[test]
+    fn test_parse() {
+        // nop, then a record route option with two of nine slots filled
+        let mut options = vec![1];
+        options.extend_from_slice(&RecordMode::Route.option_bytes()[..39]);
+        options[3] = 12;
+        options[4..12].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        let route = parse(&options).unwrap();
+        assert_eq!(
+            route.addresses,
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+        assert!(route.timestamps.is_empty());
+
+        // timestamp with addresses, one pair filled and three hops overflowed
+        let mut options = RecordMode::TimestampAndAddress.option_bytes();
+        options[2] = 13;
+        options[3] = 0x31;
+        options[4..12].copy_from_slice(&[192, 0, 2, 1, 0, 0, 1, 0]);
+        let route = parse(&options).unwrap();
+        assert_eq!(route.addresses, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+        assert_eq!(route.timestamps, vec![256]);
+        assert_eq!(route.overflow, 3);
+
+        // no options, or a truncated one
+        assert_eq!(parse(&[]), None);
+        assert_eq!(parse(&[7, 39, 4]), None);
+    }
+}
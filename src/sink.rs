@@ -0,0 +1,313 @@
+// Where send_pings delivers results: the unbounded channel returned by
+// PingerBuilder::build, a bounded queue that sheds results by an OverflowPolicy
+// once the consumer falls behind, or the callbacks of a ResultHandler
+use std::collections::VecDeque;
+use std::sync::mpsc::{RecvError, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use PingResult;
+
+// callbacks PingerBuilder::build_with_handler delivers results to, in the style of
+// go-fastping's OnRecv and OnIdle.  They're called from the thread sending the
+// pings, so a slow handler holds up the next round
+pub trait ResultHandler: Send {
+    // a reply: Receive, Timestamp, Neighbor, NodeInfo or Discovered
+    fn on_receive(&mut self, result: PingResult);
+
+    // an Idle target that didn't answer this round
+    fn on_idle(&mut self, result: PingResult);
+
+    // any other result, e.g. StateChanged.  Ignored by default
+    fn on_event(&mut self, _result: PingResult) {}
+
+    // the RoundComplete summing up a round, once its results have all been delivered.
+    // Ignored by default
+    fn on_round_complete(&mut self, _result: PingResult) {}
+}
+
+// what a bounded results queue does with a new result when it's full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // discard the oldest queued result to make room
+    DropOldest,
+    // discard the new result
+    DropNewest,
+    // wait for the consumer to make room, holding up the pinger
+    Block,
+}
+
+struct Queue {
+    results: VecDeque<PingResult>,
+    // live senders, the receiver is disconnected once they're all gone
+    senders: usize,
+    // the receiver's gone, sends fail
+    closed: bool,
+    dropped: u64,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    policy: OverflowPolicy,
+}
+
+// the pinger's end of a bounded results queue
+pub struct BoundedSender {
+    shared: Arc<Shared>,
+}
+
+// receiving end of the bounded results queue returned by
+// PingerBuilder::build_bounded, with the same methods and errors as an mpsc Receiver
+pub struct BoundedReceiver {
+    shared: Arc<Shared>,
+}
+
+// a bounded results queue holding at most capacity results
+pub fn bounded(capacity: usize, policy: OverflowPolicy) -> (BoundedSender, BoundedReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            results: VecDeque::new(),
+            senders: 1,
+            closed: false,
+            dropped: 0,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.max(1),
+        policy,
+    });
+    (
+        BoundedSender {
+            shared: shared.clone(),
+        },
+        BoundedReceiver { shared },
+    )
+}
+
+impl BoundedSender {
+    pub fn send(&self, result: PingResult) -> Result<(), String> {
+        let shared = &self.shared;
+        let mut queue = shared.queue.lock().unwrap();
+        if shared.policy == OverflowPolicy::Block {
+            while queue.results.len() >= shared.capacity && !queue.closed {
+                queue = shared.not_full.wait(queue).unwrap();
+            }
+        }
+        if queue.closed {
+            return Err("results receiver disconnected".to_string());
+        }
+        if queue.results.len() >= shared.capacity {
+            queue.dropped += 1;
+            match shared.policy {
+                OverflowPolicy::DropOldest => {
+                    queue.results.pop_front();
+                }
+                _ => return Ok(()),
+            }
+        }
+        queue.results.push_back(result);
+        shared.not_empty.notify_one();
+        Ok(())
+    }
+}
+
+impl Clone for BoundedSender {
+    fn clone(&self) -> BoundedSender {
+        self.shared.queue.lock().unwrap().senders += 1;
+        BoundedSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for BoundedSender {
+    fn drop(&mut self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            self.shared.not_empty.notify_all();
+        }
+    }
+}
+
+impl BoundedReceiver {
+    // wait for the next result, failing once the queue is empty and the pinger gone
+    pub fn recv(&self) -> Result<PingResult, RecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(result) = self.pop(&mut queue) {
+                return Ok(result);
+            }
+            if queue.senders == 0 {
+                return Err(RecvError);
+            }
+            queue = self.shared.not_empty.wait(queue).unwrap();
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<PingResult, TryRecvError> {
+        let mut queue = self.shared.queue.lock().unwrap();
+        match self.pop(&mut queue) {
+            Some(result) => Ok(result),
+            None if queue.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<PingResult, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.queue.lock().unwrap();
+        loop {
+            if let Some(result) = self.pop(&mut queue) {
+                return Ok(result);
+            }
+            if queue.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self
+                .shared
+                .not_empty
+                .wait_timeout(queue, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    // the results queued right now, without waiting for more
+    pub fn try_iter(&self) -> impl Iterator<Item = PingResult> + '_ {
+        ::std::iter::from_fn(move || self.try_recv().ok())
+    }
+
+    // results discarded so far because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.shared.queue.lock().unwrap().dropped
+    }
+
+    fn pop(&self, queue: &mut MutexGuard<Queue>) -> Option<PingResult> {
+        let result = queue.results.pop_front()?;
+        self.shared.not_full.notify_one();
+        Some(result)
+    }
+}
+
+impl Drop for BoundedReceiver {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.not_full.notify_all();
+    }
+}
+
+// where send_pings delivers results
+#[derive(Clone)]
+pub enum ResultSink {
+    Channel(Sender<PingResult>),
+    Bounded(BoundedSender),
+    Handler(Arc<Mutex<Box<dyn ResultHandler>>>),
+}
+
+impl ResultSink {
+    pub fn send(&self, result: PingResult) -> Result<(), String> {
+        match *self {
+            ResultSink::Channel(ref sender) => sender.send(result).map_err(|e| e.to_string()),
+            ResultSink::Bounded(ref sender) => sender.send(result),
+            ResultSink::Handler(ref handler) => {
+                deliver(&mut **handler.lock().unwrap(), result);
+                Ok(())
+            }
+        }
+    }
+}
+
+// hand a result to the handler's callback for its kind
+pub fn deliver(handler: &mut dyn ResultHandler, result: PingResult) {
+    match result {
+        PingResult::Idle { .. } => handler.on_idle(result),
+        PingResult::RoundComplete { .. } => handler.on_round_complete(result),
+        PingResult::Receive { .. }
+        | PingResult::Timestamp { .. }
+        | PingResult::Neighbor { .. }
+        | PingResult::NodeInfo { .. }
+        | PingResult::Discovered { .. } => handler.on_receive(result),
+        _ => handler.on_event(result),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use socket::Binding;
+    use std::net::IpAddr;
+    use std::thread;
+    use target::Probe;
+
+    fn idle(n: u32) -> PingResult {
+        PingResult::Idle {
+            addr: "127.0.0.1".parse::<IpAddr>().unwrap(),
+            binding: Binding::default(),
+            attempts: n,
+            round_id: 1,
+            probe: Probe::Echo,
+        }
+    }
+
+    fn attempts(result: PingResult) -> u32 {
+        match result {
+            PingResult::Idle { attempts, .. } => attempts,
+            _ => panic!("unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_overflow() {
+        let (sender, receiver) = bounded(2, OverflowPolicy::DropOldest);
+        for n in 1..=3 {
+            sender.send(idle(n)).unwrap();
+        }
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(
+            receiver.try_iter().map(attempts).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+
+        let (sender, receiver) = bounded(2, OverflowPolicy::DropNewest);
+        for n in 1..=3 {
+            sender.send(idle(n)).unwrap();
+        }
+        assert_eq!(receiver.dropped(), 1);
+        assert_eq!(
+            receiver.try_iter().map(attempts).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        drop(sender);
+        assert_eq!(receiver.try_recv().err(), Some(TryRecvError::Disconnected));
+    }
+
+    #[test]
+    fn test_block() {
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+        let handle = thread::spawn(move || {
+            for n in 1..=3 {
+                sender.send(idle(n)).unwrap();
+            }
+        });
+        let mut received = Vec::new();
+        while let Ok(result) = receiver.recv() {
+            received.push(attempts(result));
+        }
+        handle.join().unwrap();
+        assert_eq!(received, vec![1, 2, 3]);
+        assert_eq!(receiver.dropped(), 0);
+
+        // a full queue whose receiver is gone fails rather than blocking
+        let (sender, receiver) = bounded(1, OverflowPolicy::Block);
+        sender.send(idle(1)).unwrap();
+        drop(receiver);
+        assert!(sender.send(idle(2)).is_err());
+    }
+}
@@ -0,0 +1,555 @@
+use capture::Capture;
+use libc;
+use neighbor::NeighborChannel;
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::transport::transport_channel;
+use pnet::transport::TransportChannelType::Layer4;
+use pnet::transport::TransportProtocol::{Ipv4, Ipv6};
+use pnet::transport::{TransportReceiver, TransportSender};
+use pnet_sys;
+use route::RecordMode;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tcp::Connector;
+
+// source address and network interface a pair of icmp sockets is bound to.
+// The default binding leaves both up to the kernel's routing decision.
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Binding {
+    pub source: Option<IpAddr>,
+    pub interface: Option<String>,
+}
+
+// sending sockets of a pinger: an icmp and an icmpv6 channel per binding.  Only
+// the default binding's sockets are read from, the others are send only
+#[derive(Default)]
+pub struct Senders {
+    pub tx: BTreeMap<Binding, Channel>,
+    pub txv6: BTreeMap<Binding, Channel>,
+    // datalink channels for neighbor probes, one per binding
+    pub neighbor: BTreeMap<Binding, NeighborChannel>,
+    // targets using each binding, whose sockets are closed once the last one's gone
+    pub users: BTreeMap<Binding, usize>,
+    // network namespace sockets opened per probe (tcp) are created in
+    pub netns: Option<PathBuf>,
+    // polls the tcp probes in flight, started with the first one
+    pub connector: Option<Connector>,
+    // packet capture echo requests are copied to, if any
+    pub capture: Option<Arc<Mutex<Capture>>>,
+    // header including sockets for targets with a hand-crafted ip header
+    #[cfg(feature = "layer3")]
+    pub raw: Option<TransportSender>,
+    #[cfg(feature = "layer3")]
+    pub rawv6: Option<TransportSender>,
+}
+
+// an icmp message read off a raw socket, with the ip header stripped
+pub struct Received<'a> {
+    pub packet: &'a [u8],
+    pub addr: IpAddr,
+    // ttl (hop limit for ipv6) the packet arrived with
+    pub ttl: Option<u8>,
+    // address the packet was sent to, from the ipv4 header or the ipv6 packet info
+    pub destination: Option<IpAddr>,
+    // options from the ipv4 header, if any
+    pub ip_options: &'a [u8],
+}
+
+// read the next icmp message from the socket.  Unlike libpnet's packet iterators
+// this keeps the ttl and destination from the ipv4 header, or the hop limit and
+// packet info control messages for ipv6
+pub fn recv(receiver: &mut TransportReceiver, ipv6: bool) -> Result<Received<'_>, io::Error> {
+    let mut storage: pnet_sys::SockAddrStorage = unsafe { mem::zeroed() };
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: receiver.buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: receiver.buffer.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = (&mut storage as *mut pnet_sys::SockAddrStorage) as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<pnet_sys::SockAddrStorage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(receiver.socket.fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let buffer = &receiver.buffer[..len as usize];
+    let addr = match pnet_sys::sockaddr_to_addr(&storage, msg.msg_namelen as usize)? {
+        SocketAddr::V4(sa) => IpAddr::V4(*sa.ip()),
+        SocketAddr::V6(sa) => IpAddr::V6(*sa.ip()),
+    };
+
+    if ipv6 {
+        let (mut ttl, mut destination) = (None, None);
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::IPPROTO_IPV6
+                    && (*cmsg).cmsg_type == libc::IPV6_HOPLIMIT
+                {
+                    let hop_limit =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                    ttl = Some(hop_limit as u8);
+                } else if (*cmsg).cmsg_level == libc::IPPROTO_IPV6
+                    && (*cmsg).cmsg_type == libc::IPV6_PKTINFO
+                {
+                    let info =
+                        ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::in6_pktinfo);
+                    destination = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        return Ok(Received {
+            packet: buffer,
+            addr,
+            ttl,
+            destination,
+            ip_options: &[],
+        });
+    }
+
+    // raw ipv4 sockets deliver the ip header along with the icmp message
+    match Ipv4Packet::new(buffer) {
+        Some(header) if header.get_header_length() as usize * 4 <= buffer.len() => {
+            let header_len = header.get_header_length() as usize * 4;
+            Ok(Received {
+                packet: &buffer[header_len..],
+                addr,
+                ttl: Some(header.get_ttl()),
+                destination: Some(IpAddr::V4(header.get_destination())),
+                ip_options: &buffer[20.min(header_len)..header_len],
+            })
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("truncated ipv4 packet from {}", addr),
+        )),
+    }
+}
+
+// sender end of a socket along with the per-packet options last applied to it,
+// so targets sharing the socket only cost a setsockopt when their options differ
+pub struct Channel {
+    pub tx: TransportSender,
+    ipv6: bool,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+    record: Option<RecordMode>,
+}
+
+impl Channel {
+    pub fn new(tx: TransportSender, ipv6: bool) -> Channel {
+        Channel {
+            tx,
+            ipv6,
+            ttl: None,
+            tos: None,
+            record: None,
+        }
+    }
+
+    // set the ttl (hop limit for ipv6), tos (traffic class for ipv6) and ipv4
+    // record option of the next packets sent.  None restores the kernel default
+    pub fn apply(
+        &mut self,
+        ttl: Option<u8>,
+        tos: Option<u8>,
+        record: Option<RecordMode>,
+    ) -> Result<(), io::Error> {
+        let fd = self.tx.socket.fd;
+        if ttl != self.ttl {
+            set_ttl(fd, self.ipv6, ttl)?;
+            self.ttl = ttl;
+        }
+        if tos != self.tos {
+            set_tos(fd, self.ipv6, tos)?;
+            self.tos = tos;
+        }
+        if record != self.record && !self.ipv6 {
+            let options = record.map_or(Vec::new(), RecordMode::option_bytes);
+            let res = unsafe {
+                libc::setsockopt(
+                    fd,
+                    libc::IPPROTO_IP,
+                    libc::IP_OPTIONS,
+                    options.as_ptr() as *const libc::c_void,
+                    options.len() as libc::socklen_t,
+                )
+            };
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            self.record = record;
+        }
+        Ok(())
+    }
+}
+
+// set the ttl (hop limit for ipv6) of packets sent on a socket.  None restores
+// the kernel default
+pub fn set_ttl(fd: pnet_sys::CSocket, ipv6: bool, ttl: Option<u8>) -> Result<(), io::Error> {
+    let value = ttl.map_or(-1, libc::c_int::from);
+    if ipv6 {
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, value)
+    } else {
+        set_option(fd, libc::IPPROTO_IP, libc::IP_TTL, value)
+    }
+}
+
+// set the tos (traffic class for ipv6) of packets sent on a socket.  None restores
+// the kernel default
+pub fn set_tos(fd: pnet_sys::CSocket, ipv6: bool, tos: Option<u8>) -> Result<(), io::Error> {
+    if ipv6 {
+        let value = tos.map_or(-1, libc::c_int::from);
+        set_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, value)
+    } else {
+        let value = tos.map_or(0, libc::c_int::from);
+        set_option(fd, libc::IPPROTO_IP, libc::IP_TOS, value)
+    }
+}
+
+// make a socket send only by dropping everything it would receive, so a reply
+// isn't queued again on the socket of every binding it matches
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn discard_input(fd: pnet_sys::CSocket) -> Result<(), io::Error> {
+    // a classic bpf program keeping 0 bytes of every packet
+    let mut filter = [libc::sock_filter {
+        code: (libc::BPF_RET | libc::BPF_K) as u16,
+        jt: 0,
+        jf: 0,
+        k: 0,
+    }];
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            (&program as *const libc::sock_fprog) as *const libc::c_void,
+            mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // drain whatever was queued before the filter went on
+    let mut buffer = [0u8; 1];
+    while unsafe {
+        libc::recv(
+            fd,
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+            libc::MSG_DONTWAIT,
+        )
+    } >= 0
+    {}
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn discard_input(_fd: pnet_sys::CSocket) -> Result<(), io::Error> {
+    Ok(())
+}
+
+// allow sending to broadcast addresses on a socket
+pub fn set_broadcast(fd: pnet_sys::CSocket) -> Result<(), io::Error> {
+    set_option(fd, libc::SOL_SOCKET, libc::SO_BROADCAST, 1)
+}
+
+// open a raw icmp (or icmpv6) transport channel and apply the binding to its socket
+pub fn open_channel(
+    netns: Option<&Path>,
+    ipv6: bool,
+    binding: &Binding,
+) -> Result<(TransportSender, TransportReceiver), io::Error> {
+    let binding = binding.clone();
+    in_netns(netns, move || open_bound_channel(ipv6, &binding))
+}
+
+// run open on a helper thread that has joined the network namespace, if any, so
+// the sockets it creates live there while the calling thread stays in its own
+pub fn in_netns<T, F>(netns: Option<&Path>, open: F) -> Result<T, io::Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, io::Error> + Send + 'static,
+{
+    let netns = match netns {
+        Some(netns) => netns.to_path_buf(),
+        None => return open(),
+    };
+    let helper = thread::spawn(move || {
+        enter_netns(&netns)?;
+        open()
+    });
+    match helper.join() {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::other("network namespace helper thread panicked")),
+    }
+}
+
+fn open_bound_channel(
+    ipv6: bool,
+    binding: &Binding,
+) -> Result<(TransportSender, TransportReceiver), io::Error> {
+    let protocol = if ipv6 {
+        Layer4(Ipv6(IpNextHeaderProtocols::Icmpv6))
+    } else {
+        Layer4(Ipv4(IpNextHeaderProtocols::Icmp))
+    };
+    let (tx, rx) = transport_channel(4096, protocol)?;
+
+    if ipv6 {
+        // ipv6 raw sockets don't see the ip header, ask for the hop limit and
+        // destination separately
+        set_option(tx.socket.fd, libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT, 1)?;
+        set_option(tx.socket.fd, libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO, 1)?;
+    }
+    bind(tx.socket.fd, binding)?;
+    Ok((tx, rx))
+}
+
+// apply a binding's interface and source address to a socket
+pub fn bind(fd: pnet_sys::CSocket, binding: &Binding) -> Result<(), io::Error> {
+    if let Some(ref interface) = binding.interface {
+        bind_device(fd, interface)?;
+    }
+    if let Some(source) = binding.source {
+        bind_source(fd, source)?;
+    }
+    Ok(())
+}
+
+// move the calling thread into the network namespace at the given path,
+// e.g. /var/run/netns/<name> or /proc/<pid>/ns/net
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn enter_netns(netns: &Path) -> Result<(), io::Error> {
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+
+    let file = File::open(netns)?;
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn enter_netns(netns: &Path) -> Result<(), io::Error> {
+    Err(io::Error::other(format!(
+        "network namespace {} is only supported on linux",
+        netns.display()
+    )))
+}
+
+fn set_option(
+    fd: pnet_sys::CSocket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> Result<(), io::Error> {
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const libc::c_int) as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn bind_source(fd: pnet_sys::CSocket, source: IpAddr) -> Result<(), io::Error> {
+    let mut storage: pnet_sys::SockAddrStorage = unsafe { mem::zeroed() };
+    let len = pnet_sys::addr_to_sockaddr(SocketAddr::new(source, 0), &mut storage);
+    let res = unsafe {
+        libc::bind(
+            fd,
+            (&storage as *const pnet_sys::SockAddrStorage) as *const libc::sockaddr,
+            len,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// SO_BINDTODEVICE also covers VRFs, where the interface is the VRF master device
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn bind_device(fd: pnet_sys::CSocket, interface: &str) -> Result<(), io::Error> {
+    let name = interface.as_bytes();
+    if name.len() >= libc::IFNAMSIZ || name.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid interface name {:?}", interface),
+        ));
+    }
+    let res = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn bind_device(_fd: pnet_sys::CSocket, interface: &str) -> Result<(), io::Error> {
+    Err(io::Error::other(format!(
+        "binding to interface {} is only supported on linux",
+        interface
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn get_option(fd: pnet_sys::CSocket, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let res = unsafe {
+            libc::getsockopt(
+                fd,
+                level,
+                name,
+                (&mut value as *mut libc::c_int) as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(res, 0);
+        value
+    }
+
+    #[test]
+    fn test_channel_apply() {
+        let (tx, _) = open_channel(None, false, &Binding::default()).unwrap();
+        let mut channel = Channel::new(tx, false);
+        let fd = channel.tx.socket.fd;
+        let default_ttl = get_option(fd, libc::IPPROTO_IP, libc::IP_TTL);
+
+        channel.apply(Some(5), Some(46 << 2), None).unwrap();
+        assert_eq!(get_option(fd, libc::IPPROTO_IP, libc::IP_TTL), 5);
+        assert_eq!(get_option(fd, libc::IPPROTO_IP, libc::IP_TOS), 46 << 2);
+
+        channel.apply(None, None, None).unwrap();
+        assert_eq!(get_option(fd, libc::IPPROTO_IP, libc::IP_TTL), default_ttl);
+        assert_eq!(get_option(fd, libc::IPPROTO_IP, libc::IP_TOS), 0);
+
+        let (txv6, _) = open_channel(None, true, &Binding::default()).unwrap();
+        let mut channel = Channel::new(txv6, true);
+        let fd = channel.tx.socket.fd;
+        channel.apply(Some(7), Some(0x20), None).unwrap();
+        assert_eq!(
+            get_option(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+            7
+        );
+        assert_eq!(get_option(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS), 0x20);
+    }
+
+    #[test]
+    fn test_open_channel_bound() {
+        let binding = Binding {
+            source: Some("127.0.0.1".parse::<IpAddr>().unwrap()),
+            interface: Some("lo".to_string()),
+        };
+        assert!(open_channel(None, false, &binding).is_ok());
+
+        let binding = Binding {
+            source: None,
+            interface: Some("fpnoexist0".to_string()),
+        };
+        assert!(open_channel(None, false, &binding).is_err());
+    }
+
+    #[test]
+    fn test_recv_ttl() {
+        for &(ipv6, target) in [(false, "127.0.0.1"), (true, "::1")].iter() {
+            let (tx, mut rx) = open_channel(None, ipv6, &Binding::default()).unwrap();
+            let mut channel = Channel::new(tx, ipv6);
+            channel.apply(Some(42), None, None).unwrap();
+
+            // a bare echo request header is enough to see our own probe looped back
+            let mut probe: [u8; 8] = [if ipv6 { 128 } else { 8 }, 0, 0, 0, 0xfa, 0x57, 0, 1];
+            if !ipv6 {
+                let csum = pnet::util::checksum(&probe, 1);
+                probe[2..4].copy_from_slice(&csum.to_be_bytes());
+            }
+            let addr = target.parse::<IpAddr>().unwrap();
+            channel
+                .tx
+                .send_to(
+                    pnet::packet::icmp::IcmpPacket::new(&probe[..]).unwrap(),
+                    addr,
+                )
+                .unwrap();
+
+            // other tests share the loopback, skip anything that isn't our probe, and
+            // give up rather than hang if it was lost
+            let timeout = libc::timeval {
+                tv_sec: 2,
+                tv_usec: 0,
+            };
+            let res = unsafe {
+                libc::setsockopt(
+                    rx.socket.fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVTIMEO,
+                    (&timeout as *const libc::timeval) as *const libc::c_void,
+                    mem::size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            };
+            assert_eq!(res, 0);
+            let deadline = Instant::now() + Duration::from_secs(2);
+            loop {
+                assert!(Instant::now() < deadline, "no reply to the probe");
+                let received = recv(&mut rx, ipv6).expect("no reply to the probe");
+                if received.packet[0] == probe[0] && received.packet[4..6] == probe[4..6] {
+                    assert_eq!(received.addr, addr);
+                    assert_eq!(received.ttl, Some(42));
+                    assert_eq!(received.packet.len(), 8);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_open_channel_netns() {
+        // joining our own namespace exercises the helper thread path
+        let netns = Path::new("/proc/self/ns/net");
+        assert!(open_channel(Some(netns), true, &Binding::default()).is_ok());
+
+        let netns = Path::new("/var/run/netns/fastping-nonexistent");
+        assert!(open_channel(Some(netns), false, &Binding::default()).is_err());
+    }
+}
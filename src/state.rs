@@ -0,0 +1,233 @@
+// Per-target up/down tracking, so consumers get a PingResult::StateChanged when a
+// target's state settles on something new instead of reacting to every Idle
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+// state of a target as judged from its recent rounds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TargetState {
+    // not enough rounds yet to tell
+    Unknown,
+    Up,
+    Down,
+    // answering, but slower or lossier than the thresholds allow
+    Degraded,
+}
+
+// when a target changes state.  A target goes Down after down_after idle rounds in
+// a row and Up after up_after replies in a row, then Degraded while its rtt is
+// above degraded_rtt or its loss over the last window rounds is above degraded_loss
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StateThresholds {
+    pub(crate) down_after: u32,
+    pub(crate) up_after: u32,
+    pub(crate) degraded_rtt: Option<Duration>,
+    pub(crate) degraded_loss: Option<f64>,
+    pub(crate) window: usize,
+}
+
+impl Default for StateThresholds {
+    fn default() -> StateThresholds {
+        StateThresholds {
+            down_after: 3,
+            up_after: 2,
+            degraded_rtt: None,
+            degraded_loss: None,
+            window: 10,
+        }
+    }
+}
+
+impl StateThresholds {
+    pub fn new() -> StateThresholds {
+        StateThresholds::default()
+    }
+
+    // idle rounds in a row before a target is Down.  Default is 3
+    pub fn down_after(mut self, rounds: u32) -> StateThresholds {
+        self.down_after = rounds.max(1);
+        self
+    }
+
+    // replies in a row before a target is Up.  Default is 2
+    pub fn up_after(mut self, rounds: u32) -> StateThresholds {
+        self.up_after = rounds.max(1);
+        self
+    }
+
+    // rtt in milliseconds above which an answering target is Degraded
+    pub fn degraded_rtt(mut self, rtt: u64) -> StateThresholds {
+        self.degraded_rtt = Some(Duration::from_millis(rtt));
+        self
+    }
+
+    // fraction of the last window rounds lost above which an answering target is
+    // Degraded, e.g. 0.2 for 20%.  Clamped to 0.0..=1.0, NaN leaves it unset
+    pub fn degraded_loss(mut self, loss: f64) -> StateThresholds {
+        self.degraded_loss = if loss.is_nan() {
+            None
+        } else {
+            Some(loss.clamp(0.0, 1.0))
+        };
+        self
+    }
+
+    // rounds the loss is measured over.  Default is 10
+    pub fn window(mut self, rounds: usize) -> StateThresholds {
+        self.window = rounds.max(1);
+        self
+    }
+}
+
+// a target's state and the recent rounds it's judged on
+#[derive(Clone, Debug)]
+pub struct StateTracker {
+    thresholds: StateThresholds,
+    state: TargetState,
+    replies_in_row: u32,
+    idles_in_row: u32,
+    // whether each of the last window rounds was answered
+    recent: VecDeque<bool>,
+}
+
+impl StateTracker {
+    pub fn new(thresholds: StateThresholds) -> StateTracker {
+        StateTracker {
+            thresholds,
+            state: TargetState::Unknown,
+            replies_in_row: 0,
+            idles_in_row: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    // record a round's reply rtt, None for an idle round, and return the old and
+    // new state if it changed
+    pub fn update(&mut self, rtt: Option<Duration>) -> Option<(TargetState, TargetState)> {
+        if self.recent.len() == self.thresholds.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(rtt.is_some());
+        match rtt {
+            Some(_) => {
+                self.replies_in_row += 1;
+                self.idles_in_row = 0;
+            }
+            None => {
+                self.idles_in_row += 1;
+                self.replies_in_row = 0;
+            }
+        }
+
+        let to = match (self.state, rtt) {
+            (_, None) if self.idles_in_row >= self.thresholds.down_after => TargetState::Down,
+            (TargetState::Unknown, Some(_)) | (TargetState::Down, Some(_))
+                if self.replies_in_row < self.thresholds.up_after =>
+            {
+                self.state
+            }
+            (TargetState::Unknown, None) | (TargetState::Down, None) => self.state,
+            _ if self.degraded(rtt) => TargetState::Degraded,
+            // an idle round short of down_after leaves an Up target Up
+            (state, None) => state,
+            (_, Some(_)) => TargetState::Up,
+        };
+        if to == self.state {
+            return None;
+        }
+        let from = self.state;
+        self.state = to;
+        Some((from, to))
+    }
+
+    fn degraded(&self, rtt: Option<Duration>) -> bool {
+        let slow = match (rtt, self.thresholds.degraded_rtt) {
+            (Some(rtt), Some(threshold)) => rtt > threshold,
+            _ => false,
+        };
+        let lossy = match self.thresholds.degraded_loss {
+            Some(threshold) => {
+                let lost = self.recent.iter().filter(|&&answered| !answered).count();
+                lost as f64 / self.recent.len() as f64 > threshold
+            }
+            None => false,
+        };
+        slow || lossy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_up_down() {
+        let mut tracker = StateTracker::new(StateThresholds::new());
+        let reply = Some(Duration::from_millis(10));
+        assert_eq!(tracker.update(reply), None);
+        assert_eq!(
+            tracker.update(reply),
+            Some((TargetState::Unknown, TargetState::Up))
+        );
+        // a couple of lost rounds don't take it down
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(tracker.update(reply), None);
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(
+            tracker.update(None),
+            Some((TargetState::Up, TargetState::Down))
+        );
+        assert_eq!(tracker.update(reply), None);
+        assert_eq!(
+            tracker.update(reply),
+            Some((TargetState::Down, TargetState::Up))
+        );
+    }
+
+    #[test]
+    fn test_degraded() {
+        let thresholds = StateThresholds::new()
+            .up_after(1)
+            .degraded_rtt(100)
+            .degraded_loss(0.3)
+            .window(4);
+        let mut tracker = StateTracker::new(thresholds);
+        assert_eq!(
+            tracker.update(Some(Duration::from_millis(150))),
+            Some((TargetState::Unknown, TargetState::Degraded))
+        );
+        assert_eq!(
+            tracker.update(Some(Duration::from_millis(50))),
+            Some((TargetState::Degraded, TargetState::Up))
+        );
+        // 2 of the last 4 rounds lost
+        assert_eq!(tracker.update(Some(Duration::from_millis(50))), None);
+        assert_eq!(tracker.update(None), None);
+        assert_eq!(
+            tracker.update(None),
+            Some((TargetState::Up, TargetState::Degraded))
+        );
+        assert_eq!(tracker.state, TargetState::Degraded);
+    }
+
+    #[test]
+    fn test_degraded_loss_range() {
+        assert_eq!(
+            StateThresholds::new().degraded_loss(1.5).degraded_loss,
+            Some(1.0)
+        );
+        assert_eq!(
+            StateThresholds::new().degraded_loss(-0.2).degraded_loss,
+            Some(0.0)
+        );
+        assert_eq!(
+            StateThresholds::new().degraded_loss(f64::NAN).degraded_loss,
+            None
+        );
+    }
+}
@@ -0,0 +1,329 @@
+// Running per-target statistics built from the results send_pings produces, and the
+// snapshots taken of them
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use sink::ResultHandler;
+use socket::Binding;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use PingResult;
+use TargetState;
+
+// a target's totals so far
+#[derive(Clone, Debug, Default)]
+struct Totals {
+    rounds: u64,
+    received: u64,
+    probes: u64,
+    min_rtt: Option<Duration>,
+    max_rtt: Option<Duration>,
+    total_rtt: Duration,
+    last_rtt: Option<Duration>,
+    last_seen: Option<SystemTime>,
+    state: Option<TargetState>,
+}
+
+// a target's statistics at the time of a snapshot.  Loss is the percentage of
+// rounds the target didn't answer
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TargetStats {
+    pub addr: IpAddr,
+    pub binding: Binding,
+    pub rounds: u64,
+    pub received: u64,
+    pub lost: u64,
+    pub loss: f64,
+    // probes sent, retries included
+    pub probes: u64,
+    pub min_rtt: Option<Duration>,
+    pub avg_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    pub last_rtt: Option<Duration>,
+    pub last_seen: Option<SystemTime>,
+    // the target's state when tracked with StateThresholds
+    pub state: Option<TargetState>,
+}
+
+// statistics of every target seen, taken at one time
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct StatsSnapshot {
+    pub taken_at: SystemTime,
+    // the latest round seen
+    pub round_id: u64,
+    pub targets: Vec<TargetStats>,
+}
+
+// totals keyed the way the pinger keys its targets, so the same address reached
+// through two bindings is counted apart
+#[derive(Default)]
+struct Shared {
+    targets: BTreeMap<(IpAddr, Binding), Totals>,
+    round_id: u64,
+}
+
+// per-target statistics fed with PingResults, either through observe or as the
+// pinger's ResultHandler.  Clones share their totals
+#[derive(Clone, Default)]
+pub struct Statistics {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics::default()
+    }
+
+    // update the totals with a result.  Each target's round is counted from its
+    // reply or Idle result, so Discovered responders don't count
+    pub fn observe(&self, result: &PingResult) {
+        let mut shared = self.shared.lock().unwrap();
+        match *result {
+            PingResult::Receive {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::Timestamp {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::Neighbor {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            }
+            | PingResult::NodeInfo {
+                addr,
+                rtt,
+                ref binding,
+                attempts,
+                round_id,
+                ref reply,
+                ..
+            } => {
+                shared.round_id = shared.round_id.max(round_id);
+                let totals = shared.targets.entry((addr, binding.clone())).or_default();
+                totals.rounds += 1;
+                totals.received += 1;
+                totals.probes += attempts as u64;
+                totals.min_rtt = Some(totals.min_rtt.map_or(rtt, |min| min.min(rtt)));
+                totals.max_rtt = Some(totals.max_rtt.map_or(rtt, |max| max.max(rtt)));
+                totals.total_rtt += rtt;
+                totals.last_rtt = Some(rtt);
+                totals.last_seen = Some(reply.received_at);
+            }
+            PingResult::Idle {
+                addr,
+                ref binding,
+                attempts,
+                round_id,
+                ..
+            } => {
+                shared.round_id = shared.round_id.max(round_id);
+                let totals = shared.targets.entry((addr, binding.clone())).or_default();
+                totals.rounds += 1;
+                totals.probes += attempts as u64;
+            }
+            PingResult::StateChanged {
+                addr,
+                ref binding,
+                to,
+                ..
+            } => {
+                shared
+                    .targets
+                    .entry((addr, binding.clone()))
+                    .or_default()
+                    .state = Some(to);
+            }
+            PingResult::RoundComplete { round_id, .. } => {
+                shared.round_id = shared.round_id.max(round_id);
+            }
+            PingResult::Discovered { .. } => {}
+        }
+    }
+
+    // the statistics of a single target, as reached through the given binding
+    pub fn get(&self, addr: IpAddr, binding: &Binding) -> Option<TargetStats> {
+        let shared = self.shared.lock().unwrap();
+        shared
+            .targets
+            .get(&(addr, binding.clone()))
+            .map(|totals| target_stats(addr, binding, totals))
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let shared = self.shared.lock().unwrap();
+        StatsSnapshot {
+            taken_at: SystemTime::now(),
+            round_id: shared.round_id,
+            targets: shared
+                .targets
+                .iter()
+                .map(|((addr, binding), totals)| target_stats(*addr, binding, totals))
+                .collect(),
+        }
+    }
+
+    // drop a removed target's totals
+    pub fn forget(&self, addr: IpAddr, binding: &Binding) {
+        self.shared
+            .lock()
+            .unwrap()
+            .targets
+            .remove(&(addr, binding.clone()));
+    }
+
+    // start every target over from zero
+    pub fn reset(&self) {
+        self.shared.lock().unwrap().targets.clear();
+    }
+}
+
+impl ResultHandler for Statistics {
+    fn on_receive(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_idle(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_event(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+
+    fn on_round_complete(&mut self, result: PingResult) {
+        self.observe(&result);
+    }
+}
+
+fn target_stats(addr: IpAddr, binding: &Binding, totals: &Totals) -> TargetStats {
+    let lost = totals.rounds - totals.received;
+    TargetStats {
+        addr,
+        binding: binding.clone(),
+        rounds: totals.rounds,
+        received: totals.received,
+        lost,
+        loss: if totals.rounds == 0 {
+            0.0
+        } else {
+            lost as f64 * 100.0 / totals.rounds as f64
+        },
+        probes: totals.probes,
+        min_rtt: totals.min_rtt,
+        avg_rtt: if totals.received == 0 {
+            None
+        } else {
+            Some(totals.total_rtt / totals.received as u32)
+        },
+        max_rtt: totals.max_rtt,
+        last_rtt: totals.last_rtt,
+        last_seen: totals.last_seen,
+        state: totals.state,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ping::PingReply;
+    use socket::Binding;
+    use target::Probe;
+
+    fn receive(addr: IpAddr, binding: &Binding, rtt_ms: u64, round_id: u64) -> PingResult {
+        let received_at = SystemTime::now();
+        PingResult::Receive {
+            addr,
+            rtt: Duration::from_millis(rtt_ms),
+            binding: binding.clone(),
+            ttl: Some(64),
+            size: 64,
+            hops: Some(0),
+            route: None,
+            attempts: 1,
+            round_id,
+            reply: PingReply {
+                sequence_number: round_id as u16,
+                identifier: 7,
+                size: 64,
+                ttl: Some(64),
+                sent_at: received_at - Duration::from_millis(rtt_ms),
+                received_at,
+                probe: Probe::Echo,
+            },
+        }
+    }
+
+    #[test]
+    fn test_statistics() {
+        let addr = "192.0.2.1".parse::<IpAddr>().unwrap();
+        let binding = Binding::default();
+        let stats = Statistics::new();
+        stats.observe(&receive(addr, &binding, 2, 1));
+        stats.observe(&PingResult::Idle {
+            addr,
+            binding: Binding::default(),
+            attempts: 3,
+            round_id: 2,
+            probe: Probe::Echo,
+        });
+        stats.observe(&receive(addr, &binding, 4, 3));
+        stats.observe(&receive(addr, &binding, 6, 4));
+        // the same address through another interface is another target
+        let eth1 = Binding {
+            source: None,
+            interface: Some("eth1".to_string()),
+        };
+        stats.observe(&receive(addr, &eth1, 9, 4));
+        stats.observe(&PingResult::StateChanged {
+            addr,
+            binding: Binding::default(),
+            from: TargetState::Unknown,
+            to: TargetState::Up,
+            round_id: 4,
+            probe: Probe::Echo,
+        });
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.round_id, 4);
+        assert_eq!(snapshot.targets.len(), 2);
+        let target = stats.get(addr, &binding).unwrap();
+        assert_eq!(target, snapshot.targets[0]);
+        assert_eq!((target.rounds, target.received, target.lost), (4, 3, 1));
+        assert_eq!(target.loss, 25.0);
+        assert_eq!(target.probes, 6);
+        assert_eq!(target.min_rtt, Some(Duration::from_millis(2)));
+        assert_eq!(target.avg_rtt, Some(Duration::from_millis(4)));
+        assert_eq!(target.max_rtt, Some(Duration::from_millis(6)));
+        assert_eq!(target.last_rtt, Some(Duration::from_millis(6)));
+        assert_eq!(target.state, Some(TargetState::Up));
+
+        assert_eq!(
+            stats.get(addr, &eth1).unwrap().last_rtt,
+            Some(Duration::from_millis(9))
+        );
+
+        stats.forget(addr, &binding);
+        assert!(stats.get(addr, &binding).is_none());
+        assert!(stats.get(addr, &eth1).is_some());
+    }
+}
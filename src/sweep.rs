@@ -0,0 +1,131 @@
+// Subnet sweeps: probing every host address in a network, fping -g style, and
+// collecting the ones that answer
+use pnet::ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use target::TargetOptions;
+
+// largest network a sweep will take on
+const MAX_HOSTS: u128 = 1 << 16;
+
+// how to run a sweep with Pinger::sweep
+#[derive(Clone, Debug)]
+pub struct SweepOptions {
+    pub(crate) parallelism: usize,
+    pub(crate) interval: Duration,
+    pub(crate) retries: u32,
+    pub(crate) target: TargetOptions,
+    pub(crate) progress: Option<Sender<SweepProgress>>,
+}
+
+impl Default for SweepOptions {
+    fn default() -> SweepOptions {
+        SweepOptions {
+            parallelism: 256,
+            interval: Duration::ZERO,
+            retries: 1,
+            target: TargetOptions::default(),
+            progress: None,
+        }
+    }
+}
+
+impl SweepOptions {
+    pub fn new() -> SweepOptions {
+        SweepOptions::default()
+    }
+
+    // most addresses probed per round.  Default is 256
+    pub fn parallelism(mut self, parallelism: usize) -> SweepOptions {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    // most probes sent per second.  Default is unlimited
+    pub fn rate(mut self, rate: u32) -> SweepOptions {
+        self.interval = if rate == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs(1) / rate
+        };
+        self
+    }
+
+    // times to probe a non-responding address again, in place of the pinger's own
+    // retries.  Default is 1
+    pub fn retries(mut self, retries: u32) -> SweepOptions {
+        self.retries = retries;
+        self
+    }
+
+    // per-target options each address is probed with
+    pub fn target(mut self, target: TargetOptions) -> SweepOptions {
+        self.target = target;
+        self
+    }
+
+    // send a progress update on this channel after every round
+    pub fn progress(mut self, progress: Sender<SweepProgress>) -> SweepOptions {
+        self.progress = Some(progress);
+        self
+    }
+}
+
+// how far a sweep has got: addresses that answered or ran out of retries so far,
+// out of the total, and how many of them are alive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SweepProgress {
+    pub done: usize,
+    pub total: usize,
+    pub alive: usize,
+}
+
+// the host addresses of a network in cidr notation.  Ipv4 networks larger than a
+// /31 leave out their network and broadcast addresses
+pub fn hosts(network: &str) -> Result<Vec<IpAddr>, String> {
+    let network = network.parse::<IpNetwork>().map_err(|e| e.to_string())?;
+    let (bits, prefix) = match network {
+        IpNetwork::V4(net) => (32, net.prefix()),
+        IpNetwork::V6(net) => (128, net.prefix()),
+    };
+    let size = 1u128
+        .checked_shl(u32::from(bits - prefix))
+        .unwrap_or(u128::MAX);
+    if size > MAX_HOSTS {
+        return Err(format!(
+            "{} has more than {} addresses to sweep",
+            network, MAX_HOSTS
+        ));
+    }
+    let hosts = network.iter();
+    match network {
+        IpNetwork::V4(net) if net.prefix() < 31 => Ok(hosts
+            .filter(|&addr| {
+                addr != IpAddr::V4(net.network()) && addr != IpAddr::V4(net.broadcast())
+            })
+            .collect()),
+        _ => Ok(hosts.collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts() {
+        let hosts = hosts("192.0.2.0/30").unwrap();
+        assert_eq!(
+            hosts,
+            vec![
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "192.0.2.2".parse::<IpAddr>().unwrap()
+            ]
+        );
+        assert_eq!(super::hosts("192.0.2.4/31").unwrap().len(), 2);
+        assert_eq!(super::hosts("2001:db8::/120").unwrap().len(), 256);
+        assert!(super::hosts("2001:db8::/64").is_err());
+        assert!(super::hosts("192.0.2.0/33").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+#[cfg(feature = "layer3")]
+use layer3::IpHeader;
+use route::RecordMode;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use state::StateThresholds;
+use std::fmt;
+use std::net::IpAddr;
+
+// kind of probe sent to a target
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Probe {
+    // icmp echo request, the default
+    #[default]
+    Echo,
+    // icmp timestamp request (ipv4 only), reported as PingResult::Timestamp
+    Timestamp,
+    // tcp connect to the given port, for hosts that drop icmp.  A SYN-ACK or RST
+    // is reported as PingResult::Receive, without a ttl and with a size of 0
+    Tcp(u16),
+    // udp datagram to the given port, which should be closed.  The icmp port
+    // unreachable is reported as PingResult::Receive
+    Udp(u16),
+    // ARP request (ipv4) or Neighbor Solicitation (ipv6) for targets on a directly
+    // connected subnet, reported as PingResult::Neighbor with the target's MAC
+    Neighbor,
+    // icmpv6 node information query (ipv6 only) for the target's hostnames,
+    // reported as PingResult::NodeInfo
+    NodeInfo,
+}
+
+// short name of the probe, e.g. echo or tcp:443
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Probe::Echo => write!(f, "echo"),
+            Probe::Timestamp => write!(f, "timestamp"),
+            Probe::Tcp(port) => write!(f, "tcp:{}", port),
+            Probe::Udp(port) => write!(f, "udp:{}", port),
+            Probe::Neighbor => write!(f, "neighbor"),
+            Probe::NodeInfo => write!(f, "nodeinfo"),
+        }
+    }
+}
+
+// per-target options passed to Pinger::add_ipaddr_with.  Anything left unset
+// falls back to the pinger wide setting from the PingerBuilder
+#[derive(Clone, Debug, Default)]
+pub struct TargetOptions {
+    pub(crate) source: Option<IpAddr>,
+    pub(crate) interface: Option<String>,
+    pub(crate) ttl: Option<u8>,
+    pub(crate) tos: Option<u8>,
+    pub(crate) record: Option<RecordMode>,
+    pub(crate) probe: Probe,
+    pub(crate) discover: bool,
+    pub(crate) states: Option<StateThresholds>,
+    #[cfg(feature = "layer3")]
+    pub(crate) header: Option<IpHeader>,
+}
+
+impl TargetOptions {
+    pub fn new() -> TargetOptions {
+        TargetOptions::default()
+    }
+
+    // send probes to this target from the given source address
+    pub fn source(mut self, source: IpAddr) -> TargetOptions {
+        self.source = Some(source);
+        self
+    }
+
+    // send probes to this target out of the named interface (SO_BINDTODEVICE)
+    pub fn interface(mut self, interface: &str) -> TargetOptions {
+        self.interface = Some(interface.to_string());
+        self
+    }
+
+    // send probes to this target within the named VRF.  On linux this binds to
+    // the VRF master device, so it replaces any interface set previously
+    pub fn vrf(self, vrf: &str) -> TargetOptions {
+        self.interface(vrf)
+    }
+
+    // ip ttl (ipv6 hop limit) of probes sent to this target
+    pub fn ttl(mut self, ttl: u8) -> TargetOptions {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // ip tos (ipv6 traffic class) byte of probes sent to this target: the DSCP
+    // in the upper six bits and ECN in the lower two, e.g. 46 << 2 for EF
+    pub fn tos(mut self, tos: u8) -> TargetOptions {
+        self.tos = Some(tos);
+        self
+    }
+
+    // kind of probe to send to this target.  Default is Probe::Echo
+    pub fn probe(mut self, probe: Probe) -> TargetOptions {
+        self.probe = probe;
+        self
+    }
+
+    // treat the address as a multicast group (e.g. ff02::1) or ipv4 broadcast
+    // address and report every host that answers as PingResult::Discovered.  Link
+    // scoped groups also need an interface
+    pub fn discover(mut self) -> TargetOptions {
+        self.discover = true;
+        self
+    }
+
+    // track this target's TargetState with these thresholds instead of the
+    // pinger wide ones
+    pub fn states(mut self, thresholds: StateThresholds) -> TargetOptions {
+        self.states = Some(thresholds);
+        self
+    }
+
+    // send probes to this ipv4 target with a Record Route or Timestamp option
+    pub fn record(mut self, record: RecordMode) -> TargetOptions {
+        self.record = Some(record);
+        self
+    }
+
+    // send probes to this target with a hand-crafted ip header (layer 3 mode).
+    // Source and interface options don't apply, the header carries the source
+    #[cfg(feature = "layer3")]
+    pub fn ip_header(mut self, header: IpHeader) -> TargetOptions {
+        self.header = Some(header);
+        self
+    }
+
+    // whether probes go out with a hand-crafted ip header, which keys the target
+    // apart from ones through the regular sockets
+    #[cfg(feature = "layer3")]
+    pub(crate) fn layer3(&self) -> bool {
+        self.header.is_some()
+    }
+
+    #[cfg(not(feature = "layer3"))]
+    pub(crate) fn layer3(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,295 @@
+// TCP probes for targets behind firewalls that drop icmp.  Each probe starts a
+// non-blocking connect to the target's port and times how long the SYN-ACK (or a
+// RST for a closed port) takes, either of which shows the host is up.  A single
+// thread per pinger polls the connects in flight
+use libc;
+use ping::{Ping, ReceivedPing};
+use pnet_sys;
+use socket::{self, Binding};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// open a non-blocking tcp socket for a probe to addr with the target's binding,
+// ttl and tos applied.  The stream closes the socket when dropped
+fn open_stream(
+    addr: SocketAddr,
+    binding: &Binding,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+) -> Result<TcpStream, io::Error> {
+    let domain = if addr.is_ipv4() {
+        libc::AF_INET
+    } else {
+        libc::AF_INET6
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    stream.set_nonblocking(true)?;
+    socket::set_ttl(fd, addr.is_ipv6(), ttl)?;
+    socket::set_tos(fd, addr.is_ipv6(), tos)?;
+    socket::bind(fd, binding)?;
+    Ok(stream)
+}
+
+// open a stream and start connecting it to addr from inside netns.  Returns the
+// stream, whether the target answered straight away and when the connect started,
+// which is taken in the namespace so the helper thread isn't part of the rtt
+pub fn connect_stream(
+    netns: Option<&Path>,
+    addr: SocketAddr,
+    binding: &Binding,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+) -> Result<(TcpStream, bool, Instant, SystemTime), io::Error> {
+    let binding = binding.clone();
+    socket::in_netns(netns, move || {
+        let stream = open_stream(addr, &binding, ttl, tos)?;
+        let (started, started_at) = (Instant::now(), SystemTime::now());
+        let answered = start_connect(&stream, addr)?;
+        Ok((stream, answered, started, started_at))
+    })
+}
+
+// a connect in flight, waiting for the target to answer
+struct Pending {
+    stream: TcpStream,
+    deadline: Instant,
+    reply: ReceivedPing,
+    thread_tx: Sender<ReceivedPing>,
+}
+
+impl Pending {
+    // hand the answer to the listener channel like an icmp reply would be, if the
+    // target answered with either a SYN-ACK or a RST
+    fn answered(mut self, arrived: Instant) {
+        let addr = self.reply.addr;
+        match self.stream.take_error() {
+            Ok(None) => {}
+            Ok(Some(ref e)) if e.raw_os_error() == Some(libc::ECONNREFUSED) => {}
+            Ok(Some(e)) | Err(e) => {
+                debug!("TCP probe to {} failed: {}", addr, e);
+                return;
+            }
+        }
+        self.reply.arrived = arrived;
+        self.reply.received_at = SystemTime::now();
+        // the pinger may have stopped and dropped its receiver, nothing to do then
+        let _ = self.thread_tx.send(self.reply);
+    }
+}
+
+// polls every pending connect of a pinger from one thread, which is woken up
+// through a socket pair whenever a connect is added.  The thread exits once the
+// connector is dropped
+pub struct Connector {
+    tx: Sender<Pending>,
+    wake: UnixStream,
+}
+
+impl Connector {
+    pub fn new() -> Result<Connector, io::Error> {
+        let (wake, wake_rx) = UnixStream::pair()?;
+        wake_rx.set_nonblocking(true)?;
+        let (tx, rx) = channel();
+        thread::spawn(move || poll_connects(rx, wake_rx));
+        Ok(Connector { tx, wake })
+    }
+
+    fn add(&mut self, pending: Pending) {
+        if self.tx.send(pending).is_ok() {
+            let _ = self.wake.write(&[0]);
+        }
+    }
+}
+
+// start connecting a stream from open_stream to addr.  Returns true when the
+// target answered straight away, which loopback and refused connects can do
+fn start_connect(stream: &TcpStream, addr: SocketAddr) -> Result<bool, io::Error> {
+    let mut storage: pnet_sys::SockAddrStorage = unsafe { mem::zeroed() };
+    let len = pnet_sys::addr_to_sockaddr(addr, &mut storage);
+    let res = unsafe {
+        libc::connect(
+            stream.as_raw_fd(),
+            (&storage as *const pnet_sys::SockAddrStorage) as *const libc::sockaddr,
+            len,
+        )
+    };
+    if res == -1 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EINPROGRESS) => Ok(false),
+            Some(libc::ECONNREFUSED) => Ok(true),
+            _ => Err(e),
+        };
+    }
+    Ok(true)
+}
+
+fn poll_connects(rx: Receiver<Pending>, mut wake: UnixStream) {
+    let mut pending: Vec<Pending> = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(connect) => pending.push(connect),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+        loop {
+            match wake.read(&mut buf) {
+                Ok(0) => return,
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+        let now = Instant::now();
+        pending.retain(|connect| {
+            if connect.deadline <= now {
+                debug!("TCP probe to {} got no answer", connect.reply.addr);
+            }
+            connect.deadline > now
+        });
+        let mut pollfds: Vec<libc::pollfd> = Some(wake.as_raw_fd())
+            .into_iter()
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .chain(pending.iter().map(|connect| libc::pollfd {
+                fd: connect.stream.as_raw_fd(),
+                events: libc::POLLOUT,
+                revents: 0,
+            }))
+            .collect();
+        // wait until the next connect times out, rounded up so it has by then
+        let timeout = pending
+            .iter()
+            .map(|connect| connect.deadline - now)
+            .min()
+            .map_or(-1, |wait| {
+                wait.as_nanos()
+                    .div_ceil(1_000_000)
+                    .min(libc::c_int::MAX as u128) as libc::c_int
+            });
+        let res =
+            unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        let answered_at = Instant::now();
+        if res == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            error!("Failed to poll tcp probes: {}", e);
+            return;
+        }
+        // back to front so the indices left to visit stay valid
+        for i in (0..pending.len()).rev() {
+            if pollfds[i + 1].revents != 0 {
+                let connect = pending.swap_remove(i);
+                connect.answered(answered_at);
+            }
+        }
+    }
+}
+
+// send the next tcp probe to a target on port and return when it was sent.  The
+// connect is polled by the connector, which hands the answer to the listener channel
+pub fn send_probe(
+    netns: Option<&Path>,
+    connector: &mut Connector,
+    binding: &Binding,
+    ping: &mut Ping,
+    port: u16,
+    max_rtt: Duration,
+    thread_tx: &Sender<ReceivedPing>,
+) -> Result<(Instant, SystemTime), io::Error> {
+    let addr = SocketAddr::new(ping.get_addr(), port);
+    let (stream, answered, start_time, started_at) =
+        connect_stream(netns, addr, binding, ping.ttl, ping.tos)?;
+    let identifier = ping.get_identifier();
+    let sequence_number = ping.increment_sequence_number();
+    let pending = Pending {
+        stream,
+        deadline: start_time + max_rtt,
+        reply: ReceivedPing {
+            addr: addr.ip(),
+            binding: Some(binding.clone()),
+            identifier,
+            sequence_number,
+            arrived: start_time,
+            ttl: None,
+            size: 0,
+            route: None,
+            timestamps: None,
+            mac: None,
+            names: None,
+            received_at: SystemTime::now(),
+        },
+        thread_tx: thread_tx.clone(),
+    };
+    if answered {
+        pending.answered(Instant::now());
+    } else {
+        connector.add(pending);
+    }
+    Ok((start_time, started_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_connect() {
+        let mut connector = Connector::new().unwrap();
+        let (thread_tx, thread_rx) = channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let open = listener.local_addr().unwrap();
+        let mut ping = Ping::new(open.ip());
+        let timeout = Duration::from_secs(1);
+        for _ in 0..2 {
+            send_probe(
+                None,
+                &mut connector,
+                &Binding::default(),
+                &mut ping,
+                open.port(),
+                timeout,
+                &thread_tx,
+            )
+            .unwrap();
+        }
+        let mut answered: Vec<u16> = (0..2)
+            .map(|_| thread_rx.recv_timeout(timeout).unwrap().sequence_number)
+            .collect();
+        answered.sort();
+        assert_eq!(answered, vec![1, 2]);
+
+        // a RST from a closed port still means the host is up
+        drop(listener);
+        send_probe(
+            None,
+            &mut connector,
+            &Binding::default(),
+            &mut ping,
+            open.port(),
+            timeout,
+            &thread_tx,
+        )
+        .unwrap();
+        assert!(thread_rx.recv_timeout(timeout).is_ok());
+    }
+}
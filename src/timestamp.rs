@@ -0,0 +1,149 @@
+use ping::Ping;
+use pnet::util;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// size of an icmp timestamp request or reply
+const TIMESTAMP_LEN: usize = 20;
+
+// milliseconds in a day, the period icmp timestamps wrap around at
+const DAY_MS: i64 = 86_400_000;
+
+// the times carried by an icmp timestamp reply, in milliseconds since midnight UT
+// (RFC 792), plus when the reply arrived back.  Differences account for the
+// timestamps wrapping at midnight
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimestampReply {
+    // when we sent the request
+    pub originate: u32,
+    // when the target received it
+    pub receive: u32,
+    // when the target sent the reply
+    pub transmit: u32,
+    // when we received the reply
+    pub arrival: u32,
+}
+
+impl TimestampReply {
+    // whether the target filled in standard timestamps.  Hosts that can't report
+    // milliseconds since midnight UT set the high bit and use a time of their choosing
+    pub fn is_standard(&self) -> bool {
+        (self.receive | self.transmit) & 0x8000_0000 == 0
+    }
+
+    // milliseconds from our request to its arrival at the target.  Includes the
+    // target's clock offset, so only meaningful with synchronized clocks
+    pub fn forward_delay(&self) -> i64 {
+        wrapping_diff(self.receive, self.originate)
+    }
+
+    // milliseconds from the target sending its reply to it reaching us.  Includes
+    // the target's clock offset with the opposite sign of forward_delay
+    pub fn return_delay(&self) -> i64 {
+        wrapping_diff(self.arrival, self.transmit)
+    }
+
+    // estimated milliseconds the target's clock is ahead of ours, assuming the
+    // forward and return paths take the same time
+    pub fn clock_offset(&self) -> i64 {
+        (self.forward_delay() - self.return_delay()) / 2
+    }
+}
+
+// difference between two timestamps taken at most half a day apart
+fn wrapping_diff(later: u32, earlier: u32) -> i64 {
+    let diff = (i64::from(later) - i64::from(earlier)).rem_euclid(DAY_MS);
+    if diff > DAY_MS / 2 {
+        diff - DAY_MS
+    } else {
+        diff
+    }
+}
+
+// milliseconds since midnight UT of the given time
+pub fn ms_since_midnight(time: SystemTime) -> u32 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_millis() % DAY_MS as u128) as u32
+}
+
+// build an icmp timestamp request for the next sequence number
+pub fn timestamp_request(ping: &mut Ping) -> Vec<u8> {
+    let mut vec: Vec<u8> = vec![0; TIMESTAMP_LEN];
+    vec[0] = 13;
+    vec[4..6].copy_from_slice(&ping.get_identifier().to_be_bytes());
+    vec[6..8].copy_from_slice(&ping.increment_sequence_number().to_be_bytes());
+    vec[8..12].copy_from_slice(&ms_since_midnight(SystemTime::now()).to_be_bytes());
+
+    let csum = util::checksum(&vec, 1);
+    vec[2..4].copy_from_slice(&csum.to_be_bytes());
+    vec
+}
+
+// read the timestamps out of an icmp timestamp reply that arrived at the given time
+pub fn parse_reply(packet: &[u8], arrival: SystemTime) -> Option<TimestampReply> {
+    if packet.len() < TIMESTAMP_LEN || packet[0] != 14 {
+        return None;
+    }
+    let read =
+        |i: usize| u32::from_be_bytes([packet[i], packet[i + 1], packet[i + 2], packet[i + 3]]);
+    Some(TimestampReply {
+        originate: read(8),
+        receive: read(12),
+        transmit: read(16),
+        arrival: ms_since_midnight(arrival),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_delays() {
+        // target clock 100ms ahead, 10ms each way and 1ms to turn the request around
+        let reply = TimestampReply {
+            originate: 1000,
+            receive: 1110,
+            transmit: 1111,
+            arrival: 1021,
+        };
+        assert!(reply.is_standard());
+        assert_eq!(reply.forward_delay(), 110);
+        assert_eq!(reply.return_delay(), -90);
+        assert_eq!(reply.clock_offset(), 100);
+
+        // across midnight
+        let reply = TimestampReply {
+            originate: 86_399_995,
+            receive: 5,
+            transmit: 6,
+            arrival: 16,
+        };
+        assert_eq!(reply.forward_delay(), 10);
+        assert_eq!(reply.return_delay(), 10);
+        assert_eq!(reply.clock_offset(), 0);
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let mut packet = vec![0; TIMESTAMP_LEN];
+        packet[0] = 14;
+        packet[8..12].copy_from_slice(&1u32.to_be_bytes());
+        packet[12..16].copy_from_slice(&2u32.to_be_bytes());
+        packet[16..20].copy_from_slice(&3u32.to_be_bytes());
+        let arrival = UNIX_EPOCH + Duration::from_millis(DAY_MS as u64 * 3 + 4);
+        assert_eq!(
+            parse_reply(&packet, arrival),
+            Some(TimestampReply {
+                originate: 1,
+                receive: 2,
+                transmit: 3,
+                arrival: 4,
+            })
+        );
+        assert_eq!(parse_reply(&packet[..12], arrival), None);
+    }
+}
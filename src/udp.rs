@@ -0,0 +1,173 @@
+// UDP probes, which send a datagram to a closed port and time the ICMP Port
+// Unreachable that comes back.  Each target gets a socket of its own, whose local
+// port serves as the probe identifier, and the low bits of the sequence number set
+// the udp length, the payload being padded by up to SEQUENCE_SPAN - 1 bytes.  Both
+// are in the udp header, so the 8 bytes of the datagram every unreachable message
+// quotes are enough to read them back
+use libc;
+use ping::Ping;
+use socket::{self, Binding};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::path::Path;
+
+// number of sequence numbers told apart by the udp length, which is plenty for the
+// attempts of one round
+pub const SEQUENCE_SPAN: u16 = 16;
+
+// the udp probe a port unreachable message was sent in response to
+#[derive(Debug, PartialEq, Eq)]
+pub struct QuotedProbe {
+    pub addr: IpAddr,
+    pub identifier: u16,
+    // low bits of the sequence number, see sequence_number
+    pub sequence_number: u16,
+}
+
+// open the socket to send a target's udp probes from, bound to a local port
+// straight away so it can be used as the target's identifier
+pub fn open_socket(
+    netns: Option<&Path>,
+    ipv6: bool,
+    binding: &Binding,
+    ttl: Option<u8>,
+    tos: Option<u8>,
+) -> Result<UdpSocket, io::Error> {
+    let binding = binding.clone();
+    socket::in_netns(netns, move || {
+        let domain = if ipv6 { libc::AF_INET6 } else { libc::AF_INET };
+        let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let udp_socket = unsafe { UdpSocket::from_raw_fd(fd) };
+        socket::set_ttl(fd, ipv6, ttl)?;
+        socket::set_tos(fd, ipv6, tos)?;
+        socket::bind(fd, &binding)?;
+        if binding.source.is_none() {
+            let any = if ipv6 {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            };
+            socket::bind_source(fd, any)?;
+        }
+        Ok(udp_socket)
+    })
+}
+
+// send the next udp probe to a target on port, padded out from size bytes until
+// the length gives the sequence number away
+pub fn send_probe(ping: &mut Ping, port: u16, size: usize) -> Result<usize, io::Error> {
+    let span = SEQUENCE_SPAN as usize;
+    let low_bits = (ping.increment_sequence_number() % SEQUENCE_SPAN) as usize;
+    let padding = (low_bits + span - (8 + size) % span) % span;
+    let payload: Vec<u8> = vec![0; size + padding];
+    match ping.udp {
+        Some(ref udp_socket) => {
+            udp_socket.send_to(&payload, SocketAddr::new(ping.get_addr(), port))
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no udp socket open for target",
+        )),
+    }
+}
+
+// the latest sequence number up to sent whose low bits are those quoted in a port
+// unreachable message
+pub fn sequence_number(sent: u16, quoted: u16) -> u16 {
+    sent.wrapping_sub(sent.wrapping_sub(quoted) % SEQUENCE_SPAN)
+}
+
+// find the udp probe quoted in an icmp (icmpv6) port unreachable message
+pub fn parse_unreachable(packet: &[u8], ipv6: bool) -> Option<QuotedProbe> {
+    let (addr, udp) = if ipv6 {
+        // destination unreachable, port unreachable
+        if packet.len() < 48 || packet[0] != 1 || packet[1] != 4 || packet[8 + 6] != 17 {
+            return None;
+        }
+        let mut destination = [0; 16];
+        destination.copy_from_slice(&packet[8 + 24..8 + 40]);
+        (IpAddr::V6(Ipv6Addr::from(destination)), &packet[48..])
+    } else {
+        if packet.len() < 28 || packet[0] != 3 || packet[1] != 3 || packet[8 + 9] != 17 {
+            return None;
+        }
+        let header_len = (packet[8] & 0x0f) as usize * 4;
+        if header_len < 20 || packet.len() < 8 + header_len {
+            return None;
+        }
+        let d = &packet[8 + 16..8 + 20];
+        (
+            IpAddr::V4(Ipv4Addr::new(d[0], d[1], d[2], d[3])),
+            &packet[8 + header_len..],
+        )
+    };
+    if udp.len() < 8 {
+        return None;
+    }
+    Some(QuotedProbe {
+        addr,
+        identifier: u16::from_be_bytes([udp[0], udp[1]]),
+        sequence_number: u16::from_be_bytes([udp[4], udp[5]]) % SEQUENCE_SPAN,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unreachable() {
+        // port unreachable quoting an ipv4 header and a udp probe from port 40000
+        let mut packet = vec![3, 3, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = vec![0x45, 0, 0, 30, 0, 0, 0, 0, 64, 17, 0, 0];
+        ip_header.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&ip_header);
+        packet.extend_from_slice(&[0x9c, 0x40, 0x82, 0x9a, 0, 39, 0, 0]);
+        assert_eq!(
+            parse_unreachable(&packet, false),
+            Some(QuotedProbe {
+                addr: "10.0.0.2".parse::<IpAddr>().unwrap(),
+                identifier: 40000,
+                sequence_number: 7,
+            })
+        );
+
+        // quote cut short within the udp header
+        assert_eq!(parse_unreachable(&packet[..34], false), None);
+
+        // host unreachable isn't for us
+        packet[1] = 1;
+        assert_eq!(parse_unreachable(&packet, false), None);
+    }
+
+    #[test]
+    fn test_parse_unreachablev6() {
+        let mut packet = vec![1, 4, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = vec![0x60, 0, 0, 0, 0, 10, 17, 64];
+        ip_header.extend_from_slice(&"fe80::1".parse::<Ipv6Addr>().unwrap().octets());
+        ip_header.extend_from_slice(&"fe80::2".parse::<Ipv6Addr>().unwrap().octets());
+        packet.extend_from_slice(&ip_header);
+        packet.extend_from_slice(&[0x9c, 0x40, 0x82, 0x9a, 0, 23, 0, 0]);
+        assert_eq!(
+            parse_unreachable(&packet, true),
+            Some(QuotedProbe {
+                addr: "fe80::2".parse::<IpAddr>().unwrap(),
+                identifier: 40000,
+                sequence_number: 7,
+            })
+        );
+    }
+
+    #[test]
+    fn test_sequence_number() {
+        assert_eq!(sequence_number(7, 7), 7);
+        assert_eq!(sequence_number(18, 1), 17);
+        assert_eq!(sequence_number(18, 3), 3);
+        // across the wrap of the sequence number
+        assert_eq!(sequence_number(2, 15), 0xffff);
+    }
+}